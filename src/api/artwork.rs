@@ -4,6 +4,7 @@ use super::{
     fetch::fetch,
     tags::Tags,
 };
+use crate::config::Work;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -32,6 +33,29 @@ pub struct Artwork {
     pub urls: PixivUrls,
     pub tags: Tags,
     pub comment_count: u32,
+    #[serde(default)]
+    pub x_restrict: u32,
+    /* 2 for AI generated works */
+    #[serde(default)]
+    pub ai_type: u8,
+}
+
+impl Work for Artwork {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn x_restrict(&self) -> u32 {
+        self.x_restrict
+    }
+
+    fn is_ai(&self) -> bool {
+        self.ai_type == 2
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.tags.iter().map(|tag| tag.tag.as_str())
+    }
 }
 
 pub fn fetch_artwork(client: &ureq::Agent, id: u64) -> Result<Artwork, ApiError> {
@@ -0,0 +1,368 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::metrics;
+
+use ureq::{
+    http::{self, header, HeaderValue, Method, StatusCode},
+    middleware::{Middleware, MiddlewareNext},
+    Body, SendBody,
+};
+
+thread_local! {
+    /* Whether the request on this worker asked to skip the cache, see server::serve */
+    static BYPASS: Cell<bool> = const { Cell::new(false) };
+}
+
+/* A reload that asks for fresh data skips the cache, if the operator turned that on */
+pub fn begin_request(request: &rouille::Request, allow_bypass: bool) {
+    let no_cache = |name| {
+        request
+            .header(name)
+            .is_some_and(|value| value.to_ascii_lowercase().contains("no-cache"))
+    };
+    let bypass = allow_bypass && (no_cache("Cache-Control") || no_cache("Pragma"));
+    BYPASS.with(|current| current.set(bypass));
+}
+
+/* How long responses from each group of upstream endpoints stay valid */
+#[derive(Clone)]
+pub struct CacheTtls {
+    pub ranking: Duration,
+    pub artwork: Duration,
+    pub ugoira: Duration,
+    pub comments: Duration,
+    pub search: Duration,
+    pub user: Duration,
+    pub sketch: Duration,
+}
+
+impl Default for CacheTtls {
+    fn default() -> Self {
+        Self {
+            ranking: Duration::from_secs(10 * 60),
+            artwork: Duration::from_secs(60 * 60),
+            ugoira: Duration::from_secs(24 * 60 * 60),
+            comments: Duration::from_secs(2 * 60),
+            search: Duration::from_secs(5 * 60),
+            user: Duration::from_secs(15 * 60),
+            sketch: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+impl CacheTtls {
+    fn lookup(&self, uri: &http::Uri) -> Option<Duration> {
+        let path = uri.path();
+        let ttl = match uri.host()? {
+            "www.pixiv.net" => {
+                if path == "/ranking.php" {
+                    self.ranking
+                } else if let Some(path) = path.strip_prefix("/ajax/") {
+                    if path.starts_with("illusts/comments/") {
+                        self.comments
+                    } else if path.ends_with("/ugoira_meta") {
+                        self.ugoira
+                    } else if path.starts_with("illust/") {
+                        self.artwork
+                    } else if path.starts_with("search/") {
+                        self.search
+                    } else if path.starts_with("user/") {
+                        self.user
+                    } else {
+                        return None;
+                    }
+                } else {
+                    return None;
+                }
+            }
+            "sketch.pixiv.net" if path.starts_with("/api/") => self.sketch,
+            _ => return None,
+        };
+        (!ttl.is_zero()).then_some(ttl)
+    }
+}
+
+struct Entry {
+    content_type: Option<HeaderValue>,
+    mime_type: Option<String>,
+    data: Vec<u8>,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    /* Least recently used entries come first */
+    recency: BTreeMap<u64, String>,
+    size: usize,
+    tick: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.map.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.size -= entry.data.len();
+        }
+    }
+}
+
+/* In-memory TTL cache for pixiv ajax responses, keyed by upstream URL */
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<Entries>>,
+    ttls: CacheTtls,
+    capacity: usize,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttls: CacheTtls) -> Self {
+        Self {
+            entries: Arc::default(),
+            ttls,
+            capacity,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<http::Response<Body>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+
+        let entry = entries.map.get_mut(key)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(key);
+            return None;
+        }
+
+        entries.tick += 1;
+        let key = entries.recency.remove(&entry.last_used)?;
+        entry.last_used = entries.tick;
+        entries.recency.insert(entry.last_used, key);
+
+        let mut body = Body::builder();
+        if let Some(mime_type) = &entry.mime_type {
+            body = body.mime_type(mime_type);
+        }
+        let mut response = http::Response::builder().status(StatusCode::OK);
+        if let Some(content_type) = &entry.content_type {
+            response = response.header(header::CONTENT_TYPE, content_type);
+        }
+        response.body(body.data(entry.data.clone())).ok()
+    }
+
+    /* Body of a fresh entry without counting as a use, for pages that only show what is at hand */
+    pub fn peek(&self, url: &str) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.map.get(url)?;
+        (entry.expires > Instant::now()).then(|| entry.data.clone())
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        if entry.data.len() > self.capacity {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+
+        /* Make room by dropping the least recently used responses */
+        while entries.size + entry.data.len() > self.capacity {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            if let Some(old) = entries.map.remove(&oldest) {
+                entries.size -= old.data.len();
+            }
+        }
+
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.size += entry.data.len();
+        entries.recency.insert(tick, key.clone());
+        entries.map.insert(
+            key,
+            Entry {
+                last_used: tick,
+                ..entry
+            },
+        );
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn handle(
+        &self,
+        request: http::Request<SendBody>,
+        next: MiddlewareNext,
+    ) -> Result<http::Response<Body>, ureq::Error> {
+        if request.method() != Method::GET {
+            return next.handle(request);
+        }
+        let Some(ttl) = self.ttls.lookup(request.uri()) else {
+            return next.handle(request);
+        };
+
+        /* A bypass skips the lookup but still refreshes the entry */
+        let key = request.uri().to_string();
+        if !BYPASS.with(Cell::get) {
+            let cached = self.get(&key);
+            metrics::record_cache("response", cached.is_some());
+            if let Some(response) = cached {
+                return Ok(response);
+            }
+        }
+
+        let response = next.handle(request)?;
+        if response.status() != StatusCode::OK {
+            return Ok(response);
+        }
+
+        let (parts, mut body) = response.into_parts();
+        let mime_type = body.mime_type().map(str::to_owned);
+        let data = body
+            .with_config()
+            .limit(super::fetch::max_json_size())
+            .read_to_vec()?;
+
+        /* Failures pixiv reports in a 200 are passed on but not kept */
+        if !super::fetch::is_error(&data) {
+            self.insert(
+                key,
+                Entry {
+                    content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+                    mime_type: mime_type.clone(),
+                    data: data.clone(),
+                    expires: Instant::now() + ttl,
+                    last_used: 0,
+                },
+            );
+        }
+
+        let mut body = Body::builder();
+        if let Some(mime_type) = mime_type {
+            body = body.mime_type(mime_type);
+        }
+        Ok(http::Response::from_parts(parts, body.data(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: usize) -> Entry {
+        Entry {
+            content_type: None,
+            mime_type: None,
+            data: vec![0; size],
+            expires: Instant::now() + Duration::from_secs(60),
+            last_used: 0,
+        }
+    }
+
+    #[test]
+    fn endpoint_ttls() {
+        let ttls = CacheTtls::default();
+        let ttl = |url: &str| ttls.lookup(&url.parse().unwrap());
+
+        assert_eq!(
+            ttl("https://www.pixiv.net/ranking.php?mode=daily&p=1&format=json"),
+            Some(ttls.ranking)
+        );
+        assert_eq!(
+            ttl("https://www.pixiv.net/ajax/illust/97276742"),
+            Some(ttls.artwork)
+        );
+        assert_eq!(
+            ttl("https://www.pixiv.net/ajax/illust/97276742/ugoira_meta?lang=en"),
+            Some(ttls.ugoira)
+        );
+        assert_eq!(
+            ttl("https://www.pixiv.net/ajax/illusts/comments/roots?illust_id=1"),
+            Some(ttls.comments)
+        );
+        assert_eq!(ttl("https://i.pximg.net/img-master/img/1_p0.jpg"), None);
+        assert_eq!(ttl("https://www.pixiv.net/fanbox/creator/1"), None);
+    }
+
+    #[test]
+    fn forced_reloads_bypass_the_cache() {
+        let reload = rouille::Request::fake_http(
+            "GET",
+            "/",
+            vec![("Cache-Control".into(), "no-cache".into())],
+            vec![],
+        );
+        let bypass = |request, allow| {
+            begin_request(request, allow);
+            BYPASS.with(Cell::get)
+        };
+
+        assert!(bypass(&reload, true));
+        assert!(!bypass(&reload, false));
+        assert!(!bypass(&rouille::Request::fake_http("GET", "/", vec![], vec![]), true));
+    }
+
+    #[test]
+    fn error_responses_are_recognized() {
+        use crate::api::fetch::is_error;
+        assert!(is_error(br#"{"error":true,"message":"","body":[]}"#));
+        assert!(!is_error(br#"{"error":false,"message":"","body":{}}"#));
+        assert!(!is_error(br#"{"contents":[],"mode":"daily"}"#));
+        assert!(!is_error(b"<html>"));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ResponseCache::new(30, CacheTtls::default());
+        cache.insert("a".into(), entry(10));
+        cache.insert("b".into(), entry(10));
+        cache.insert("c".into(), entry(10));
+
+        /* Touch "a" so "b" becomes the oldest entry */
+        assert!(cache.get("a").is_some());
+        cache.insert("d".into(), entry(10));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("d").is_some());
+        assert_eq!(cache.entries.lock().unwrap().size, 30);
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let cache = ResponseCache::new(30, CacheTtls::default());
+        cache.insert(
+            "a".into(),
+            Entry {
+                expires: Instant::now(),
+                ..entry(10)
+            },
+        );
+
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.entries.lock().unwrap().size, 0);
+    }
+
+    #[test]
+    fn peeking_keeps_recency() {
+        let cache = ResponseCache::new(20, CacheTtls::default());
+        /* Keys are written the way the middleware stores them */
+        let url = "https://www.pixiv.net/ranking.php?mode=daily&p=1&format=json&date=20240101";
+        let key = url.parse::<http::Uri>().unwrap().to_string();
+        cache.insert(key, entry(10));
+        cache.insert("b".into(), entry(10));
+
+        assert_eq!(cache.peek(url).map(|data| data.len()), Some(10));
+        cache.insert("c".into(), entry(10));
+        assert!(cache.peek(url).is_none());
+        assert!(cache.peek("b").is_some());
+    }
+}
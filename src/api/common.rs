@@ -1,4 +1,5 @@
 use super::de::{deserialize_number_unconditionally, strip_url_prefix};
+use crate::config::Work;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -19,8 +20,10 @@ pub struct PixivSearchResult {
         deserialize_with = "deserialize_number_unconditionally"
     )]
     pub user_id: u64,
+    #[allow(dead_code)]
     pub user_name: String,
     /* Note: This appears to always be empty */
+    #[allow(dead_code)]
     pub description: String,
     pub update_date: String,
     pub create_date: String,
@@ -33,4 +36,27 @@ pub struct PixivSearchResult {
     pub width: u32,
     pub height: u32,
     pub is_masked: bool,
+    /* 2 for AI generated works */
+    #[serde(default)]
+    pub ai_type: u8,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Work for PixivSearchResult {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn x_restrict(&self) -> u32 {
+        self.r18
+    }
+
+    fn is_ai(&self) -> bool {
+        self.ai_type == 2
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(String::as_str)
+    }
 }
@@ -17,7 +17,9 @@ where
         where
             E: serde::de::Error,
         {
-            Ok(value.parse().unwrap())
+            value
+                .parse()
+                .map_err(|_| E::invalid_value(Unexpected::Str(value), &self))
         }
         fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
         where
@@ -114,3 +116,125 @@ impl Expected for ValidPixivImageUrl {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    /* Property tests feeding arbitrary upstream data to the deserializers */
+
+    use proptest::prelude::*;
+    use serde::{de::DeserializeOwned, Deserialize};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::api::{
+        artwork::Artwork,
+        comments::PixivComments,
+        common::ApiResponse,
+        ranking::Ranking,
+        search::PixivSearch,
+        sketch::{SketchApiResponse, SketchItem},
+        user::{PixivBookmarks, PixivIllustrations, PixivUser},
+    };
+
+    /* Leaves mostly look like what pixiv sends, numbers as strings, urls and dates */
+    fn leaf() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<u64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            any::<String>().prop_map(Value::from),
+            "[0-9]{0,24}".prop_map(Value::from),
+            "https://(i|s|img-sketch)\\.(pximg|pixiv)\\.net/[a-z0-9/._-]{0,32}"
+                .prop_map(Value::from),
+            "[0-9]{8}".prop_map(Value::from),
+        ]
+    }
+
+    fn json() -> impl Strategy<Value = Value> {
+        leaf().prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+                prop::collection::hash_map("[a-zA-Z_0-9]{0,12}", inner, 0..8)
+                    .prop_map(|map| Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    /* Field names pixiv uses, so the custom deserializers actually run */
+    const FIELDS: &[&str] = &[
+        "id", "userId", "illust_id", "url", "title", "prev_date", "next_date", "date", "illusts",
+        "manga", "works", "body", "error", "contents", "rank_total", "xRestrict", "urls",
+        "original", "imageBig", "img", "stampId", "total",
+    ];
+
+    fn shaped() -> impl Strategy<Value = Value> {
+        let field = (prop::sample::select(FIELDS), json());
+        prop::collection::vec(field, 0..12).prop_map(|fields| {
+            let object = fields
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect::<serde_json::Map<_, _>>();
+            json!({
+                "error": false,
+                "body": object.clone(),
+                "contents": [object.clone()],
+                "data": object,
+            })
+        })
+    }
+
+    /* Only proves that parsing returns, whatever it returns */
+    fn parse<T: DeserializeOwned>(value: &Value) {
+        let _ = serde_json::from_value::<T>(value.clone());
+        let _ = serde_json::from_str::<T>(&value.to_string());
+    }
+
+    fn parse_all(value: &Value) {
+        parse::<ApiResponse<Artwork>>(value);
+        parse::<ApiResponse<PixivComments>>(value);
+        parse::<ApiResponse<PixivSearch>>(value);
+        parse::<ApiResponse<PixivIllustrations>>(value);
+        parse::<ApiResponse<PixivBookmarks>>(value);
+        parse::<ApiResponse<PixivUser>>(value);
+        parse::<SketchApiResponse<SketchItem>>(value);
+        parse::<Ranking>(value);
+    }
+
+    proptest! {
+        #[test]
+        fn deserializers_never_panic(value in prop_oneof![json(), shaped()]) {
+            parse_all(&value);
+        }
+
+        #[test]
+        fn raw_bodies_never_panic(body in any::<String>()) {
+            let _ = serde_json::from_str::<Ranking>(&body);
+            let _ = serde_json::from_str::<ApiResponse<PixivSearch>>(&body);
+        }
+    }
+
+    #[test]
+    fn bad_numbers_are_errors() {
+        #[derive(Deserialize)]
+        struct Id {
+            #[serde(deserialize_with = "deserialize_number_unconditionally")]
+            #[allow(dead_code)]
+            id: u64,
+        }
+        assert!(serde_json::from_value::<Id>(json!({ "id": "12" })).is_ok());
+        assert!(serde_json::from_value::<Id>(json!({ "id": "not a number" })).is_err());
+        assert!(serde_json::from_value::<Id>(json!({ "id": "-1" })).is_err());
+
+        let ranking = json!({
+            "contents": [],
+            "date": "20240101",
+            "prev_date": null,
+            "next_date": false,
+            "rank_total": 0,
+        });
+        let ranking = serde_json::from_value::<Ranking>(ranking).unwrap();
+        assert!(ranking.prev_date.is_none() && ranking.next_date.is_none());
+    }
+}
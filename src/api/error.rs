@@ -4,16 +4,53 @@ use std::borrow::Cow;
 pub enum ApiError {
     External(u16, Cow<'static, str>),
     Internal(Cow<'static, str>),
+    /* pixiv answered 429, with the seconds from Retry-After if it sent any */
+    RateLimited(Option<u64>),
+}
+
+impl ApiError {
+    /* HTTP status of the error page, upstream codes that aren't errors become a 502 */
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::External(code, _) if (400..600).contains(code) => *code,
+            Self::External(..) => 502,
+            Self::Internal(_) => 500,
+            Self::RateLimited(_) => 429,
+        }
+    }
 }
 
 impl From<ureq::Error> for ApiError {
     fn from(err: ureq::Error) -> Self {
         match err {
+            ureq::Error::StatusCode(429) => Self::RateLimited(None),
             ureq::Error::StatusCode(code) => Self::External(
                 code,
                 "".into()
             ),
-            _ => Self::Internal("Unknown Error".into()),
+            ureq::Error::Timeout(_) => Self::External(504, "pixiv took too long to respond".into()),
+            ureq::Error::BodyExceedsLimit(limit) => Self::External(
+                502,
+                format!("pixiv sent more than the {} KiB this instance accepts", limit / 1024).into(),
+            ),
+            ureq::Error::HostNotFound
+            | ureq::Error::ConnectionFailed
+            | ureq::Error::Io(_) => Self::External(502, format!("Can't reach pixiv: {err}").into()),
+            _ => Self::Internal(err.to_string().into()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_keep_their_status() {
+        assert_eq!(ApiError::External(451, "".into()).status_code(), 451);
+        assert_eq!(ApiError::External(302, "".into()).status_code(), 502);
+        assert_eq!(ApiError::Internal("".into()).status_code(), 500);
+        assert_eq!(ApiError::RateLimited(Some(30)).status_code(), 429);
+        assert_eq!(ApiError::from(ureq::Error::StatusCode(404)).status_code(), 404);
+    }
+}
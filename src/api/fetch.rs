@@ -1,7 +1,30 @@
-use crate::api::{common::ApiResponse, error::ApiError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::api::{common::ApiResponse, error::ApiError, retry::RetryPolicy, session};
 use serde::Deserialize;
 use ureq::Body;
 
+/* Largest API response in bytes, set from the config at startup */
+static MAX_JSON_SIZE: AtomicU64 = AtomicU64::new(8 * 1024 * 1024);
+
+pub fn set_max_json_size(bytes: u64) {
+    MAX_JSON_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn max_json_size() -> u64 {
+    MAX_JSON_SIZE.load(Ordering::Relaxed)
+}
+
+/* Ajax responses that report a failure despite their 200 status */
+pub(crate) fn is_error(data: &[u8]) -> bool {
+    #[derive(Deserialize)]
+    struct Status {
+        #[serde(default)]
+        error: bool,
+    }
+    serde_json::from_slice::<Status>(data).is_ok_and(|status| status.error)
+}
+
 fn fetch_json_internal<T>(response: ureq::http::Response<Body>) -> Result<T, ApiError>
 where
     T: for<'a> Deserialize<'a>,
@@ -9,25 +32,49 @@ where
     let status = response.status();
     let mut body = response.into_body();
     if !status.is_success() {
-        Err(ApiError::External(
-            status.as_u16(),
-            body.read_to_string().map_or("".into(), |s| s.into()),
-        ))
+        /* Error bodies are usually ajax responses carrying a message */
+        let message = body
+            .with_config()
+            .limit(max_json_size())
+            .read_to_string()
+            .unwrap_or_default();
+        let message = serde_json::from_str::<ApiResponse<serde_json::Value>>(&message)
+            .ok()
+            .and_then(|response| response.message)
+            .unwrap_or(message);
+        Err(ApiError::External(status.as_u16(), message.into()))
     } else {
-        match body.read_json::<T>() {
-            Ok(res) => Ok(res),
-            Err(err) => Err(ApiError::Internal(err.to_string().into())),
+        let data = body.with_config().limit(max_json_size()).read_to_vec()?;
+        /* An expired session is usually only told about in the body */
+        if is_error(&data) {
+            session::report_error_body();
         }
+        serde_json::from_slice::<T>(&data).map_err(|err| {
+            ApiError::External(
+                502,
+                format!("pixiv sent an unexpected response: {err}").into(),
+            )
+        })
     }
 }
 pub(crate) fn fetch_json<T>(client: &ureq::Agent, url: &str) -> Result<T, ApiError>
 where
     T: for<'a> Deserialize<'a>,
 {
-    println!("Fetching URL: {}", url);
-    fetch_json_internal(client.get(url).call()?)
+    log::info!(target: crate::logging::UPSTREAM, "Fetching URL: {}", url);
+    /* Statuses are handled here so 429s keep their Retry-After header */
+    let response = RetryPolicy::get().run(|| {
+        client
+            .get(url)
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .call()
+    })?;
+    fetch_json_internal(response)
 }
 
+#[allow(dead_code)]
 pub(crate) fn post_and_fetch_json<T>(
     client: &ureq::Agent,
     url: &str,
@@ -36,7 +83,7 @@ pub(crate) fn post_and_fetch_json<T>(
 where
     T: for<'a> Deserialize<'a>,
 {
-    println!("Fetching URL: {}", url);
+    log::info!(target: crate::logging::UPSTREAM, "Fetching URL: {}", url);
     fetch_json_internal(
         client
             .post(url)
@@ -52,12 +99,11 @@ where
 {
     let response = fetch_json::<ApiResponse<T>>(client, url)?;
 
-    if response.error || response.body.is_none() {
-        Err(ApiError::External(
+    match response.body {
+        Some(body) if !response.error => Ok(body),
+        _ => Err(ApiError::External(
             400,
             response.message.map_or("".into(), |s| s.into()),
-        ))
-    } else {
-        Ok(response.body.unwrap())
+        )),
     }
 }
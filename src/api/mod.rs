@@ -1,11 +1,14 @@
 pub mod artwork;
+pub mod cache;
 pub mod comments;
 pub mod common;
 pub mod de;
 pub mod error;
-mod fetch;
+pub mod fetch;
 pub mod ranking;
+pub mod retry;
 pub mod search;
+pub mod session;
 pub mod sketch;
 pub mod tags;
 #[cfg(feature = "ugoira")]
@@ -1,6 +1,13 @@
 use std::{fmt, str::FromStr};
 
-use super::{de::strip_url_prefix, error::ApiError, fetch::fetch_json};
+use super::{
+    cache::ResponseCache,
+    de::{deserialize_number_unconditionally, strip_url_prefix},
+    error::ApiError,
+    fetch::fetch_json,
+};
+use chrono::NaiveDate;
+use crate::{config::Work, get_param_or_num};
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer,
@@ -21,7 +28,7 @@ fn string_or_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    struct StringOrNone(Option<String>);
+    struct StringOrNone;
 
     impl Visitor<'_> for StringOrNone {
         type Value = Option<String>;
@@ -34,7 +41,14 @@ where
         where
             E: de::Error,
         {
-            Ok(Some(FromStr::from_str(value).unwrap()))
+            Ok(Some(value.to_owned()))
+        }
+
+        fn visit_unit<E>(self) -> Result<Option<String>, E>
+        where
+            E: de::Error,
+        {
+            Ok(None)
         }
 
         fn visit_bool<E>(self, _: bool) -> Result<Option<String>, E>
@@ -45,7 +59,7 @@ where
         }
     }
 
-    deserializer.deserialize_any(StringOrNone(None))
+    deserializer.deserialize_any(StringOrNone)
 }
 
 #[derive(Deserialize)]
@@ -56,21 +70,287 @@ pub struct RankingItem {
     pub illust_id: u32,
     pub width: u32,
     pub height: u32,
-    pub illust_page_count: String,
+    #[serde(deserialize_with = "deserialize_number_unconditionally")]
+    pub illust_page_count: u64,
     pub illust_upload_timestamp: u64,
+    pub rank: u32,
+    /* Rank of the previous day, 0 for works new to the ranking */
+    #[serde(default)]
+    pub yes_rank: u32,
+    pub user_id: u64,
+    #[serde(default)]
+    pub user_name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /* 0 for illustrations, 1 for manga and 2 for ugoira */
+    #[serde(default, deserialize_with = "deserialize_number_unconditionally")]
+    pub illust_type: u64,
+    /* 2 for AI generated works */
+    #[serde(default)]
+    pub illust_ai_type: u8,
+    /* Rarely sent, every work in a ranking has at least the restriction of its mode */
+    #[serde(default)]
+    pub x_restrict: u32,
+}
+
+impl Work for RankingItem {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn x_restrict(&self) -> u32 {
+        self.x_restrict
+    }
+
+    fn is_ai(&self) -> bool {
+        self.illust_ai_type == 2
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(String::as_str)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RankingMode {
+    #[default]
+    Daily,
+    Weekly,
+    Monthly,
+    Rookie,
+    Original,
+    Male,
+    Female,
+    DailyAi,
+    DailyR18,
+    WeeklyR18,
+    MaleR18,
+    FemaleR18,
+    R18g,
+    DailyR18Ai,
+}
+
+impl RankingMode {
+    pub const ALL: [Self; 14] = [
+        Self::Daily,
+        Self::Weekly,
+        Self::Monthly,
+        Self::Rookie,
+        Self::Original,
+        Self::Male,
+        Self::Female,
+        Self::DailyAi,
+        Self::DailyR18,
+        Self::WeeklyR18,
+        Self::MaleR18,
+        Self::FemaleR18,
+        Self::R18g,
+        Self::DailyR18Ai,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+            Self::Rookie => "rookie",
+            Self::Original => "original",
+            Self::Male => "male",
+            Self::Female => "female",
+            Self::DailyAi => "daily_ai",
+            Self::DailyR18 => "daily_r18",
+            Self::WeeklyR18 => "weekly_r18",
+            Self::MaleR18 => "male_r18",
+            Self::FemaleR18 => "female_r18",
+            Self::R18g => "r18g",
+            Self::DailyR18Ai => "daily_r18_ai",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Daily => "Daily",
+            Self::Weekly => "Weekly",
+            Self::Monthly => "Monthly",
+            Self::Rookie => "Rookie",
+            Self::Original => "Original",
+            Self::Male => "Popular among males",
+            Self::Female => "Popular among females",
+            Self::DailyAi => "AI-generated",
+            Self::DailyR18 => "Daily R-18",
+            Self::WeeklyR18 => "Weekly R-18",
+            Self::MaleR18 => "R-18 popular among males",
+            Self::FemaleR18 => "R-18 popular among females",
+            Self::R18g => "R-18G",
+            Self::DailyR18Ai => "AI-generated R-18",
+        }
+    }
+
+    /* Same levels as x_restrict, 1 for R-18 and 2 for R-18G */
+    pub fn x_restrict(&self) -> u32 {
+        match self {
+            Self::DailyR18 | Self::WeeklyR18 | Self::MaleR18 | Self::FemaleR18 | Self::DailyR18Ai => 1,
+            Self::R18g => 2,
+            _ => 0,
+        }
+    }
+}
+
+impl FromStr for RankingMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|mode| mode.as_str() == s).ok_or(())
+    }
+}
+
+impl fmt::Display for RankingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/* Content types a ranking can be narrowed to, `All` sends no content parameter */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RankingContent {
+    #[default]
+    All,
+    Illust,
+    Ugoira,
+    Manga,
 }
 
-pub fn fetch_ranking(
-    client: &ureq::Agent,
-    date: Option<&String>,
-    page: u32,
-) -> Result<Ranking, ApiError> {
-    let date = date.map(|d| format!("&date={}", d));
-    let url = format!(
-        "https://www.pixiv.net/ranking.php?mode=daily&p={}&format=json{}",
-        page,
+impl RankingContent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Illust => "illust",
+            Self::Ugoira => "ugoira",
+            Self::Manga => "manga",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::All => "All",
+            Self::Illust => "Illustrations",
+            Self::Ugoira => "Ugoira",
+            Self::Manga => "Manga",
+        }
+    }
+}
+
+impl FromStr for RankingContent {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::All, Self::Illust, Self::Ugoira, Self::Manga]
+            .into_iter()
+            .find(|content| content.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for RankingContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl RankingMode {
+    /* What pixiv accepts for each mode, anything else is answered with an error */
+    pub fn contents(&self) -> &'static [RankingContent] {
+        use RankingContent::*;
+        match self {
+            Self::Daily | Self::Weekly | Self::DailyR18 | Self::WeeklyR18 => {
+                &[All, Illust, Ugoira, Manga]
+            }
+            Self::Monthly | Self::Rookie | Self::R18g => &[All, Illust, Manga],
+            _ => &[All],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RankingRequest {
+    pub mode: RankingMode,
+    pub content: RankingContent,
+    pub date: Option<NaiveDate>,
+    pub page: u32,
+}
+
+/* Dates as pixiv writes them, e.g. 20240131 */
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y%m%d").ok()
+}
+
+/* Unknown modes are refused, content types the mode doesn't have fall back to all */
+impl TryFrom<&rouille::Request> for RankingRequest {
+    type Error = ApiError;
+
+    fn try_from(req: &rouille::Request) -> Result<Self, ApiError> {
+        let mode = match req.get_param("mode") {
+            Some(mode) => RankingMode::from_str(&mode)
+                .map_err(|_| ApiError::External(400, "Unknown ranking mode".into()))?,
+            None => RankingMode::default(),
+        };
+        Ok(Self {
+            mode,
+            content: req
+                .get_param("content")
+                .and_then(|s| RankingContent::from_str(&s).ok())
+                .filter(|content| mode.contents().contains(content))
+                .unwrap_or_default(),
+            date: req.get_param("date").and_then(|date| parse_date(&date)),
+            page: get_param_or_num!(req, "p", 1),
+        })
+    }
+}
+
+impl RankingRequest {
+    /* Query string of this ranking without the page, for links and feeds */
+    pub fn query(&self) -> String {
+        let mut query = format!("mode={}", self.mode);
+        if self.content != RankingContent::All {
+            query.push_str(&format!("&content={}", self.content));
+        }
+        if let Some(date) = &self.date {
+            query.push_str(&format!("&date={}", date.format("%Y%m%d")));
+        }
+        query
+    }
+}
+
+fn ranking_url(request: &RankingRequest) -> String {
+    let content = match request.content {
+        RankingContent::All => String::new(),
+        content => format!("&content={content}"),
+    };
+    let date = request
+        .date
+        .map(|d| format!("&date={}", d.format("%Y%m%d")));
+    format!(
+        "https://www.pixiv.net/ranking.php?mode={}{}&p={}&format=json{}",
+        request.mode,
+        content,
+        request.page,
         date.unwrap_or_default()
-    );
+    )
+}
+
+fn with_restriction(mut ranking: Ranking, mode: RankingMode) -> Ranking {
+    for item in &mut ranking.contents {
+        item.x_restrict = item.x_restrict.max(mode.x_restrict());
+    }
+    ranking
+}
+
+pub fn fetch_ranking(client: &ureq::Agent, request: &RankingRequest) -> Result<Ranking, ApiError> {
+    let ranking = fetch_json::<Ranking>(client, &ranking_url(request))?;
+    Ok(with_restriction(ranking, request.mode))
+}
 
-    fetch_json::<Ranking>(client, &url)
+/* Only what the response cache already holds, pixiv is never asked */
+pub fn cached_ranking(cache: &ResponseCache, request: &RankingRequest) -> Option<Ranking> {
+    let data = cache.peek(&ranking_url(request))?;
+    let ranking = serde_json::from_slice::<Ranking>(&data).ok()?;
+    Some(with_restriction(ranking, request.mode))
 }
@@ -0,0 +1,194 @@
+use std::{
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use ureq::{
+    http::{self, header, StatusCode},
+    Body,
+};
+
+use super::error::ApiError;
+
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/* Upper bounds for the configuration, a worker waits out every backoff */
+pub const MAX_ATTEMPTS: u32 = 10;
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/* How idempotent upstream requests are repeated when they fail */
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /* Retries after the first attempt */
+    pub attempts: u32,
+    /* Wait before the first retry, doubled for every further one */
+    pub backoff: Duration,
+    /* Longest Retry-After worth waiting for instead of failing right away */
+    pub max_wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 2,
+            backoff: Duration::from_millis(250),
+            max_wait: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /* Sets the policy used by all API requests, only the first call has an effect */
+    pub fn install(self) {
+        let _ = POLICY.set(self);
+    }
+
+    pub fn get() -> &'static RetryPolicy {
+        POLICY.get_or_init(RetryPolicy::default)
+    }
+
+    /* Runs `request` until it succeeds, fails permanently or runs out of attempts */
+    pub fn run(
+        &self,
+        mut request: impl FnMut() -> Result<http::Response<Body>, ureq::Error>,
+    ) -> Result<http::Response<Body>, ApiError> {
+        let mut attempt = 0;
+        loop {
+            let retries_left = attempt < self.attempts;
+            let backoff = self
+                .backoff
+                .checked_mul(2u32.saturating_pow(attempt))
+                .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+            attempt += 1;
+
+            match request() {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let wait = retry_after(response.headers(), SystemTime::now());
+                    /* The retry usually goes out with another session from the pool */
+                    match wait {
+                        Some(wait) if retries_left && wait <= self.max_wait => {
+                            std::thread::sleep(wait)
+                        }
+                        None if retries_left => std::thread::sleep(backoff),
+                        _ => return Err(ApiError::RateLimited(wait.map(|w| w.as_secs()))),
+                    }
+                }
+                Ok(response) if response.status().is_server_error() && retries_left => {
+                    std::thread::sleep(backoff)
+                }
+                Err(error) if retries_left && is_transient(&error) => std::thread::sleep(backoff),
+                Ok(response) => return Ok(response),
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+fn is_transient(error: &ureq::Error) -> bool {
+    matches!(
+        error,
+        ureq::Error::Io(_)
+            | ureq::Error::Timeout(_)
+            | ureq::Error::HostNotFound
+            | ureq::Error::ConnectionFailed
+            | ureq::Error::StatusCode(500..=599)
+    )
+}
+
+/* Retry-After is either a number of seconds or an HTTP date */
+fn retry_after(headers: &http::HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let date = SystemTime::UNIX_EPOCH + Duration::from_secs(date.timestamp().try_into().ok()?);
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, retry_after: Option<&str>) -> http::Response<Body> {
+        let mut response = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header(header::RETRY_AFTER, retry_after);
+        }
+        response.body(Body::builder().data("")).unwrap()
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 2,
+            backoff: Duration::ZERO,
+            max_wait: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1445412480);
+        let headers = |value: &str| response(429, Some(value)).headers().clone();
+
+        assert_eq!(
+            retry_after(&headers("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:30 GMT"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(retry_after(&headers("soon"), now), None);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let mut calls = 0;
+        let result = policy().run(|| {
+            calls += 1;
+            Ok(response(if calls < 3 { 503 } else { 200 }, None))
+        });
+        assert_eq!(result.unwrap().status(), 200);
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = policy().run(|| {
+            calls += 1;
+            Err(ureq::Error::ConnectionFailed)
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn huge_backoffs_are_capped() {
+        let policy = RetryPolicy {
+            attempts: 40,
+            backoff: Duration::from_secs(u64::MAX / 2),
+            max_wait: Duration::ZERO,
+        };
+        /* The first attempt succeeds, computing its backoff must not overflow */
+        assert!(policy.run(|| Ok(response(200, None))).is_ok());
+    }
+
+    #[test]
+    fn gives_up_on_long_rate_limits() {
+        let mut calls = 0;
+        let result = policy().run(|| {
+            calls += 1;
+            Ok(response(429, Some("60")))
+        });
+        assert!(matches!(result, Err(ApiError::RateLimited(Some(60)))));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result = policy().run(|| {
+            calls += 1;
+            Ok(response(if calls < 2 { 429 } else { 200 }, Some("0")))
+        });
+        assert_eq!(result.unwrap().status(), 200);
+        assert_eq!(calls, 2);
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use ureq::{
+    http::{self, header, HeaderValue},
+    middleware::{Middleware, MiddlewareNext},
+    Body, SendBody,
+};
+
+use super::error::ApiError;
+
+/* Retired sessions stay out of rotation for at most this long */
+const MAX_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    /* Session that served the latest upstream request made on this thread */
+    static LAST_USED: RefCell<Option<(SessionPool, usize)>> = const { RefCell::new(None) };
+}
+
+/* Blames a failure pixiv only reports in the response body on the session that received it */
+pub fn report_error_body() {
+    if let Some((pool, index)) = LAST_USED.with(|last| last.borrow_mut().take()) {
+        pool.report_error_body(index);
+    }
+}
+
+/* Obtains an anonymous PHPSESSID from the front page */
+pub fn fetch_guest_cookie(client: &ureq::Agent) -> Result<String, ApiError> {
+    let res = client.get("https://www.pixiv.net/en/").call()?;
+
+    let cookie = res
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .find(|h| h.starts_with("PHPSESSID"))
+        .ok_or_else(|| ApiError::External(502, "No session cookie obtained".into()))?;
+
+    let cookie = cookie.split_once("; ").map_or(cookie, |(cookie, _)| cookie);
+    Ok(cookie.to_owned())
+}
+
+struct Session {
+    cookie: HeaderValue,
+    failures: u32,
+    retired_until: Option<Instant>,
+    requests: u64,
+    /* Anonymous sessions are replaced when pixiv stops accepting them */
+    guest: bool,
+    refreshing: bool,
+}
+
+#[derive(Serialize)]
+pub struct SessionStatus {
+    pub guest: bool,
+    pub healthy: bool,
+    pub failures: u32,
+    /* Seconds until a retired session is tried again */
+    pub retry_in: u64,
+    pub requests: u64,
+}
+
+/* Rotates pixiv sessions and retires the ones pixiv starts rejecting */
+#[derive(Clone)]
+pub struct SessionPool {
+    sessions: Arc<Mutex<Vec<Session>>>,
+    next: Arc<AtomicUsize>,
+    cooldown: Duration,
+    /* Plain agent used to obtain new guest sessions */
+    guest_agent: Option<ureq::Agent>,
+}
+
+impl SessionPool {
+    pub fn new(cookies: &[String], cooldown: Duration) -> Result<Self, ApiError> {
+        let sessions = cookies
+            .iter()
+            .map(|cookie| {
+                let cookie = HeaderValue::from_str(cookie)
+                    .map_err(|_| ApiError::Internal("Invalid cookie".into()))?;
+                Ok(Session {
+                    cookie,
+                    failures: 0,
+                    retired_until: None,
+                    requests: 0,
+                    guest: false,
+                    refreshing: false,
+                })
+            })
+            .collect::<Result<_, ApiError>>()?;
+
+        Ok(Self {
+            sessions: Arc::new(Mutex::new(sessions)),
+            next: Arc::default(),
+            cooldown,
+            guest_agent: None,
+        })
+    }
+
+    /* A guest session that is renewed when rejected and every `interval` */
+    pub fn guest(
+        agent: ureq::Agent,
+        cooldown: Duration,
+        interval: Option<Duration>,
+    ) -> Result<Self, ApiError> {
+        let cookie = fetch_guest_cookie(&agent)?;
+        let mut pool = Self::new(&[cookie], cooldown)?;
+        pool.sessions.lock().unwrap()[0].guest = true;
+        pool.guest_agent = Some(agent);
+
+        if let Some(interval) = interval {
+            let pool = pool.clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                let count = pool.sessions.lock().unwrap().len();
+                for index in 0..count {
+                    pool.renew(index);
+                }
+            });
+        }
+
+        Ok(pool)
+    }
+
+    /* Fetches a replacement for a guest session in the background */
+    fn renew(&self, index: usize) {
+        let Some(agent) = self.guest_agent.clone() else {
+            return;
+        };
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions.get_mut(index) {
+                Some(session) if session.guest && !session.refreshing => {
+                    session.refreshing = true;
+                }
+                _ => return,
+            }
+        }
+
+        let pool = self.clone();
+        std::thread::spawn(move || {
+            let cookie = fetch_guest_cookie(&agent);
+            pool.replace(index, cookie);
+        });
+    }
+
+    fn replace(&self, index: usize, cookie: Result<String, ApiError>) {
+        let cookie = cookie.and_then(|cookie| {
+            HeaderValue::from_str(&cookie).map_err(|_| ApiError::Internal("Invalid cookie".into()))
+        });
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(index) else {
+            return;
+        };
+        session.refreshing = false;
+        match cookie {
+            Ok(cookie) => {
+                session.cookie = cookie;
+                session.failures = 0;
+                session.retired_until = None;
+            }
+            Err(error) => log::warn!("Failed to renew guest session: {error}"),
+        }
+    }
+
+    /* Next healthy session, or the one that recovers first if all are retired */
+    fn pick(&self) -> Option<(usize, HeaderValue)> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+        let index = (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|&i| sessions[i].retired_until.is_none_or(|until| until <= now))
+            .or_else(|| (0..count).min_by_key(|&i| sessions[i].retired_until))?;
+
+        let session = &mut sessions[index];
+        session.requests += 1;
+        Some((index, session.cookie.clone()))
+    }
+
+    fn report(&self, index: usize, healthy: bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(index) else {
+            return;
+        };
+
+        if healthy {
+            session.failures = 0;
+            session.retired_until = None;
+        } else {
+            /* Back off exponentially while the session keeps failing */
+            let cooldown = self
+                .cooldown
+                .checked_mul(2u32.saturating_pow(session.failures.min(16)))
+                .map_or(MAX_COOLDOWN, |cooldown| cooldown.min(MAX_COOLDOWN));
+            session.failures += 1;
+            session.retired_until = Some(Instant::now() + cooldown);
+
+            if session.guest {
+                drop(sessions);
+                self.renew(index);
+            }
+        }
+    }
+
+    /* Missing works are reported the same way, so configured sessions stay in rotation */
+    fn report_error_body(&self, index: usize) {
+        let guest = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(index)
+            .is_some_and(|session| session.guest);
+        if guest {
+            self.renew(index);
+        }
+    }
+
+    pub fn status(&self) -> Vec<SessionStatus> {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|session| {
+                let retry_in = session
+                    .retired_until
+                    .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+                SessionStatus {
+                    guest: session.guest,
+                    healthy: retry_in.is_zero(),
+                    failures: session.failures,
+                    retry_in: retry_in.as_secs(),
+                    requests: session.requests,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Middleware for SessionPool {
+    fn handle(
+        &self,
+        mut request: http::Request<SendBody>,
+        next: MiddlewareNext,
+    ) -> Result<http::Response<Body>, ureq::Error> {
+        let Some((index, cookie)) = self.pick() else {
+            return next.handle(request);
+        };
+        request.headers_mut().append(header::COOKIE, cookie);
+        LAST_USED.with(|last| *last.borrow_mut() = Some((self.clone(), index)));
+
+        /* Only API hosts say anything about the session, image hosts don't care */
+        let judged = matches!(
+            request.uri().host(),
+            Some("www.pixiv.net" | "sketch.pixiv.net")
+        );
+
+        let result = next.handle(request);
+        if judged {
+            self.report(index, is_healthy(&result));
+        }
+
+        result
+    }
+}
+
+/* Redirects and 204s are normal answers, only an empty 200 hints at a dead session */
+fn is_healthy(result: &Result<http::Response<Body>, ureq::Error>) -> bool {
+    match result {
+        Err(ureq::Error::StatusCode(401 | 403 | 429)) => false,
+        Ok(response) => match response.status().as_u16() {
+            401 | 403 | 429 => false,
+            200 => response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .is_none_or(|length| length != "0"),
+            _ => true,
+        },
+        /* Connection problems aren't the session's fault */
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(count: usize) -> SessionPool {
+        let cookies: Vec<String> = (0..count).map(|i| format!("PHPSESSID={i}")).collect();
+        SessionPool::new(&cookies, Duration::from_secs(60)).unwrap()
+    }
+
+    #[test]
+    fn rotates_between_healthy_sessions() {
+        let pool = pool(3);
+        let picked: Vec<usize> = (0..6).map(|_| pool.pick().unwrap().0).collect();
+        assert_eq!(picked, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn skips_retired_sessions() {
+        let pool = pool(2);
+        pool.report(0, false);
+        assert!((0..4).all(|_| pool.pick().unwrap().0 == 1));
+
+        let status = pool.status();
+        assert!(!status[0].healthy);
+        assert_eq!(status[0].failures, 1);
+        assert!(status[1].healthy);
+
+        pool.report(0, true);
+        assert!(pool.status()[0].healthy);
+    }
+
+    #[test]
+    fn replaces_guest_session() {
+        let pool = pool(1);
+        pool.sessions.lock().unwrap()[0].guest = true;
+        pool.report(0, false);
+        assert!(!pool.status()[0].healthy);
+
+        pool.replace(0, Ok("PHPSESSID=new".into()));
+        assert!(pool.status()[0].healthy);
+        assert_eq!(pool.pick().unwrap().1, "PHPSESSID=new");
+    }
+
+    #[test]
+    fn error_bodies_keep_configured_sessions() {
+        let pool = pool(1);
+        LAST_USED.with(|last| *last.borrow_mut() = Some((pool.clone(), 0)));
+        report_error_body();
+
+        assert!(pool.status()[0].healthy);
+        assert!(LAST_USED.with(|last| last.borrow().is_none()));
+    }
+
+    #[test]
+    fn long_cooldowns_are_capped() {
+        let cookies = ["PHPSESSID=0".to_owned()];
+        let pool = SessionPool::new(&cookies, Duration::from_secs(u64::MAX / 2)).unwrap();
+        pool.report(0, false);
+        pool.report(0, false);
+        assert!(pool.status()[0].retry_in <= MAX_COOLDOWN.as_secs());
+    }
+
+    #[test]
+    fn empty_redirects_keep_the_session() {
+        let empty = |status: u16| {
+            let response = http::Response::builder()
+                .status(status)
+                .header(header::CONTENT_LENGTH, "0")
+                .body(Body::builder().data(Vec::new()))
+                .unwrap();
+            is_healthy(&Ok(response))
+        };
+        assert!(empty(302));
+        assert!(empty(204));
+        assert!(!empty(200));
+        assert!(!empty(403));
+    }
+
+    #[test]
+    fn falls_back_to_first_recovering_session() {
+        let pool = pool(2);
+        pool.report(0, false);
+        pool.report(1, false);
+        pool.report(1, false);
+
+        /* Session 1 failed twice and is retired for longer */
+        assert_eq!(pool.pick().unwrap().0, 0);
+        assert!(pool.status().iter().all(|status| !status.healthy));
+    }
+}
@@ -3,6 +3,7 @@ use super::{
     error::ApiError,
     fetch::{fetch_json, post_and_fetch_json},
 };
+use crate::config::Work;
 
 use serde::{Deserialize, Serialize};
 use std::{borrow::Cow, fmt::Write};
@@ -15,6 +16,7 @@ pub struct SketchImage {
     pub url: String,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct SketchShortMedia {
     #[serde(deserialize_with = "strip_url_prefix")]
@@ -72,6 +74,7 @@ pub struct SketchUser {
     pub stats: Option<SketchUserStats>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct SketchUserPosts {
     #[serde(deserialize_with = "deserialize_number_unconditionally")]
@@ -93,6 +96,24 @@ pub struct SketchItem {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+impl Work for SketchItem {
+    fn user_id(&self) -> u64 {
+        self.user.pixiv_user_id
+    }
+
+    fn x_restrict(&self) -> u32 {
+        self.is_r18.into()
+    }
+
+    fn is_ai(&self) -> bool {
+        false
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(String::as_str)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct SketchImpressions {
     pub feedbacks: Vec<SketchImpression>,
@@ -131,11 +152,30 @@ pub struct SketchLive {
     pub chat_count: u32,
 }
 
+impl Work for SketchLive {
+    fn user_id(&self) -> u64 {
+        self.user.pixiv_user_id
+    }
+
+    fn x_restrict(&self) -> u32 {
+        self.is_r18.into()
+    }
+
+    fn is_ai(&self) -> bool {
+        false
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &str> {
+        std::iter::empty()
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct SketchWall {
     pub items: Vec<SketchItem>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct SketchLiveOwner {
     pub hls_movie: SketchShortMedia,
@@ -176,6 +216,7 @@ pub fn fetch_user(
 }
 
 // https://sketch.pixiv.net/api/users/posts/latest.json
+#[allow(dead_code)]
 pub fn fetch_latest_user_posts(
     client: &ureq::Agent,
     ids: &[u64],
@@ -0,0 +1,939 @@
+use std::{fmt, net::IpAddr, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        cache::CacheTtls,
+        error::ApiError,
+        retry::{self, RetryPolicy},
+    },
+    ratelimit::{Budget, Budgets},
+};
+
+/* Environment variables are named after the key, e.g. REAPIXA_SERVER_PORT */
+const ENV_PREFIX: &str = "REAPIXA";
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub pixiv: PixivConfig,
+    pub cache: CacheConfig,
+    pub timeouts: TimeoutConfig,
+    pub limits: SizeLimitConfig,
+    pub retry: RetryConfig,
+    pub proxy: ProxyConfig,
+    pub ratelimit: RateLimitConfig,
+    pub log: LogConfig,
+    pub security: SecurityConfig,
+    pub jump: JumpConfig,
+    pub routes: RouteConfig,
+    pub content: ContentPolicy,
+    pub instance: InstanceConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    /* Public URL of this instance, used for absolute links in feeds */
+    pub host: Option<String>,
+    /* PEM certificate chain and key, HTTPS is served when both are set */
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /* Open TLS connections, further ones are closed right after being accepted */
+    pub tls_connections: usize,
+    /* Listen on this Unix socket instead of bind and port */
+    pub unix_socket: Option<PathBuf>,
+    /* Permissions of the socket file, e.g. 0o660, the umask decides if unset */
+    pub unix_socket_mode: Option<u32>,
+    /* Requests handled at the same time */
+    pub workers: usize,
+    /* Requests waiting for a worker, further ones are answered with 503 */
+    pub queue: usize,
+    /* Seconds in-flight requests get to finish after SIGTERM or SIGINT */
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0".into(),
+            port: 8000,
+            host: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_connections: 1024,
+            unix_socket: None,
+            unix_socket_mode: None,
+            workers: 32,
+            queue: 128,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PixivConfig {
+    /* Session cookies, a guest session is used if empty */
+    pub cookies: Vec<String>,
+    /* Seconds a rejected cookie sits out, doubled on every further failure */
+    pub cookie_cooldown: u64,
+    /* Seconds after which the guest session is renewed, 0 only renews rejected ones */
+    pub guest_refresh: u64,
+}
+
+impl Default for PixivConfig {
+    fn default() -> Self {
+        Self {
+            cookies: vec![],
+            cookie_cooldown: 60,
+            guest_refresh: 6 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /* Response cache size in MiB, 0 disables it */
+    pub size: usize,
+    /* Lets forced reloads skip the response cache, meant for debugging an instance */
+    pub allow_bypass: bool,
+    pub ttl: TtlConfig,
+    pub image_dir: Option<PathBuf>,
+    /* Image cache size in MiB */
+    pub image_size: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            size: 32,
+            allow_bypass: false,
+            ttl: TtlConfig::default(),
+            image_dir: None,
+            image_size: 1024,
+        }
+    }
+}
+
+/* Lifetimes of cached responses in seconds */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtlConfig {
+    pub ranking: u64,
+    pub artwork: u64,
+    pub ugoira: u64,
+    pub comments: u64,
+    pub search: u64,
+    pub user: u64,
+    pub sketch: u64,
+}
+
+impl Default for TtlConfig {
+    fn default() -> Self {
+        let ttls = CacheTtls::default();
+        Self {
+            ranking: ttls.ranking.as_secs(),
+            artwork: ttls.artwork.as_secs(),
+            ugoira: ttls.ugoira.as_secs(),
+            comments: ttls.comments.as_secs(),
+            search: ttls.search.as_secs(),
+            user: ttls.user.as_secs(),
+            sketch: ttls.sketch.as_secs(),
+        }
+    }
+}
+
+impl From<&TtlConfig> for CacheTtls {
+    fn from(ttl: &TtlConfig) -> Self {
+        Self {
+            ranking: Duration::from_secs(ttl.ranking),
+            artwork: Duration::from_secs(ttl.artwork),
+            ugoira: Duration::from_secs(ttl.ugoira),
+            comments: Duration::from_secs(ttl.comments),
+            search: Duration::from_secs(ttl.search),
+            user: Duration::from_secs(ttl.user),
+            sketch: Duration::from_secs(ttl.sketch),
+        }
+    }
+}
+
+/* Upstream timeouts in seconds, read applies to the response head and again to the body */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect: u64,
+    pub read: u64,
+    /* Image proxy and ugoira downloads */
+    pub image_connect: u64,
+    pub image_read: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect: 10,
+            read: 30,
+            image_connect: 10,
+            image_read: 120,
+        }
+    }
+}
+
+/* Caps on what is read from pixiv and produced for clients */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SizeLimitConfig {
+    /* MiB of a single API response */
+    pub json_size: u64,
+    /* MiB of the zip archive a ugoira is converted from */
+    pub ugoira_archive: u64,
+    pub ugoira_frames: usize,
+    /* MiB of the converted video */
+    pub ugoira_output: u64,
+}
+
+impl Default for SizeLimitConfig {
+    fn default() -> Self {
+        Self {
+            json_size: 8,
+            ugoira_archive: 64,
+            ugoira_frames: 500,
+            ugoira_output: 64,
+        }
+    }
+}
+
+/* Retries of failed upstream API requests */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /* Retries after the first attempt, 0 disables retrying */
+    pub attempts: u32,
+    /* Milliseconds before the first retry, doubled for every further one */
+    pub backoff_ms: u64,
+    /* Longest Retry-After in seconds that is waited out instead of failing */
+    pub max_wait: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        Self {
+            attempts: policy.attempts,
+            backoff_ms: policy.backoff.as_millis() as u64,
+            max_wait: policy.max_wait.as_secs(),
+        }
+    }
+}
+
+impl From<&RetryConfig> for RetryPolicy {
+    fn from(retry: &RetryConfig) -> Self {
+        Self {
+            attempts: retry.attempts,
+            backoff: Duration::from_millis(retry.backoff_ms),
+            max_wait: Duration::from_secs(retry.max_wait),
+        }
+    }
+}
+
+/* Outbound proxies, e.g. "socks5://127.0.0.1:9050" or "http://gateway:3128" */
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /* Used for API calls, unset falls back to HTTPS_PROXY/ALL_PROXY, "direct" uses none */
+    pub api: Option<String>,
+    /* Used for images, unset uses the API proxy */
+    pub images: Option<String>,
+}
+
+impl ProxyConfig {
+    pub fn for_api(&self) -> Result<Option<ureq::Proxy>, ConfigError> {
+        parse_proxy(self.api.as_deref())
+    }
+
+    pub fn for_images(&self) -> Result<Option<ureq::Proxy>, ConfigError> {
+        parse_proxy(self.images.as_deref().or(self.api.as_deref()))
+    }
+}
+
+fn parse_proxy(url: Option<&str>) -> Result<Option<ureq::Proxy>, ConfigError> {
+    match url {
+        None => Ok(ureq::Proxy::try_from_env()),
+        Some("direct") => Ok(None),
+        Some(url) => ureq::Proxy::new(url)
+            .map(Some)
+            .map_err(|e| ConfigError(format!("proxy: \"{url}\" is not a usable proxy: {e}"))),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /* off, error, warn, info, debug or trace */
+    pub level: String,
+    /* One JSON object per line instead of plain text */
+    pub json: bool,
+    /* Log every handled request */
+    pub access: bool,
+    /* Log the URL of every pixiv API call */
+    pub upstream_urls: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+            json: false,
+            access: true,
+            upstream_urls: false,
+        }
+    }
+}
+
+/* Security headers sent with every response */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /* CSP for pages, "{nonce}" is replaced by the per-response script nonce, empty disables it */
+    pub content_security_policy: String,
+    /* Who may embed this instance in a frame, e.g. "'none'", "'self'" or a list of origins */
+    pub frame_ancestors: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            content_security_policy: "default-src 'none'; script-src 'nonce-{nonce}'; \
+                style-src 'self' 'unsafe-inline'; img-src 'self' data:; media-src 'self'; \
+                connect-src 'self'; form-action 'self'; base-uri 'none'"
+                .into(),
+            frame_ancestors: "'none'".into(),
+            referrer_policy: "no-referrer".into(),
+            permissions_policy: "accelerometer=(), browsing-topics=(), camera=(), \
+                geolocation=(), gyroscope=(), magnetometer=(), microphone=(), payment=(), usb=()"
+                .into(),
+        }
+    }
+}
+
+/* External links opened through /jump.php, hosts match their subdomains too */
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JumpConfig {
+    /* Redirected to without the confirmation page */
+    pub allow: Vec<String>,
+    /* Never redirected to */
+    pub deny: Vec<String>,
+}
+
+impl JumpConfig {
+    pub fn allows(&self, host: &str) -> bool {
+        self.allow.iter().any(|entry| host_matches(host, entry))
+    }
+
+    pub fn denies(&self, host: &str) -> bool {
+        self.deny.iter().any(|entry| host_matches(host, entry))
+    }
+}
+
+fn host_matches(host: &str, entry: &str) -> bool {
+    let entry = entry.trim_start_matches('.');
+    host.eq_ignore_ascii_case(entry)
+        || host.len().checked_sub(entry.len() + 1).is_some_and(|dot| {
+            host.as_bytes()[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(entry)
+        })
+}
+
+/* Per client request budgets */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /* Identify clients by X-Forwarded-For, only enable behind a reverse proxy */
+    pub trust_forwarded_for: bool,
+    pub pages: LimitConfig,
+    /* Ugoira transcoding */
+    pub expensive: LimitConfig,
+    pub images: LimitConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trust_forwarded_for: false,
+            pages: LimitConfig {
+                per_minute: 120,
+                burst: 60,
+            },
+            expensive: LimitConfig {
+                per_minute: 6,
+                burst: 3,
+            },
+            images: LimitConfig {
+                per_minute: 1200,
+                burst: 300,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /* Sustained requests per minute, 0 means unlimited */
+    pub per_minute: u32,
+    /* Requests allowed in a row before the rate applies */
+    pub burst: u32,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            per_minute: 0,
+            burst: 1,
+        }
+    }
+}
+
+impl From<&LimitConfig> for Budget {
+    fn from(limit: &LimitConfig) -> Self {
+        Self {
+            per_second: f64::from(limit.per_minute) / 60.0,
+            burst: f64::from(limit.burst.max(1)),
+        }
+    }
+}
+
+impl From<&RateLimitConfig> for Budgets {
+    fn from(limits: &RateLimitConfig) -> Self {
+        Self {
+            pages: (&limits.pages).into(),
+            expensive: (&limits.expensive).into(),
+            images: (&limits.images).into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    pub search: bool,
+    pub users: bool,
+    pub artworks: bool,
+    pub comments: bool,
+    pub redirects: bool,
+    pub sketch: bool,
+    pub ugoira: bool,
+    pub rss: bool,
+    pub imageproxy: bool,
+    pub settings: bool,
+    pub metrics: bool,
+}
+
+impl Default for RouteConfig {
+    fn default() -> Self {
+        Self {
+            search: true,
+            users: true,
+            artworks: true,
+            comments: true,
+            redirects: true,
+            sketch: true,
+            ugoira: true,
+            rss: true,
+            imageproxy: true,
+            settings: true,
+            metrics: true,
+        }
+    }
+}
+
+impl RouteConfig {
+    pub fn allows(&self, path: &str) -> bool {
+        let groups = [
+            (self.search, &["/tags/", "/search", "/scroll"][..]),
+            (self.users, &["/users/"]),
+            (self.artworks, &["/artworks/"]),
+            (self.comments, &["/comments/", "/replies/"]),
+            (self.redirects, &["/jump.php", "/member_illust.php", "/fanbox/"]),
+            (self.sketch, &["/sketch"]),
+            (self.ugoira, &["/ugoira/"]),
+            (self.rss, &["/rss"]),
+            (self.imageproxy, &["/imageproxy/", "/simg/", "/spix/", "/spxi/", "/stamp/"]),
+            (self.settings, &["/settings"]),
+            (self.metrics, &["/metrics"]),
+        ];
+        let path = path.strip_prefix("/en").unwrap_or(path);
+        groups
+            .iter()
+            .filter(|(enabled, _)| !enabled)
+            .all(|(_, prefixes)| !prefixes.iter().any(|prefix| path.starts_with(prefix)))
+    }
+}
+
+/* Shown on /about and published at /instance.json */
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstanceConfig {
+    pub name: String,
+    pub description: String,
+    /* Where the operator can be reached, an email address or a URL */
+    pub contact: Option<String>,
+    pub source: String,
+    /* Served at /robots.txt */
+    pub robots_txt: String,
+    /* X-Robots-Tag sent with every response, empty to leave it out */
+    pub robots_tag: String,
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        Self {
+            name: "Pixiv Proxy".into(),
+            description: "This is a simple Pixiv API client written in Rust.".into(),
+            contact: None,
+            source: "https://github.com/HookedBehemoth/pixiv-proxy".into(),
+            robots_txt: "User-agent: *\nDisallow: /\n".into(),
+            robots_tag: "noindex, nofollow".into(),
+        }
+    }
+}
+
+/* Rules enforced by the operator that visitors can't override */
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentPolicy {
+    /* Always search with the "safe" rating and hide R-18 and R-18G works */
+    pub safe_only: bool,
+    /* Hide works pixiv marks as AI generated */
+    pub hide_ai: bool,
+    /* pixiv user ids whose works and pages are hidden */
+    pub blocked_users: Vec<u64>,
+    /* Works with any of these tags are hidden, compared case-insensitively */
+    pub blocked_tags: Vec<String>,
+}
+
+/* What the content policy needs to know about a work */
+pub trait Work {
+    fn user_id(&self) -> u64;
+    /* 0 for all ages, 1 for R-18, 2 for R-18G */
+    fn x_restrict(&self) -> u32;
+    fn is_ai(&self) -> bool;
+    fn tags(&self) -> impl Iterator<Item = &str>;
+}
+
+impl ContentPolicy {
+    /* Nothing would be hidden, so works don't need to be looked up first */
+    pub fn hides_nothing(&self) -> bool {
+        !self.safe_only
+            && !self.hide_ai
+            && self.blocked_users.is_empty()
+            && self.blocked_tags.is_empty()
+    }
+
+    pub fn hides(&self, work: &impl Work) -> bool {
+        (self.safe_only && work.x_restrict() > 0)
+            || (self.hide_ai && work.is_ai())
+            || self.hides_user(work.user_id())
+            || work.tags().any(|tag| self.hides_tag(tag))
+    }
+
+    pub fn hides_user(&self, user_id: u64) -> bool {
+        self.blocked_users.contains(&user_id)
+    }
+
+    pub fn hides_tag(&self, tag: &str) -> bool {
+        self.blocked_tags
+            .iter()
+            .any(|blocked| blocked.to_lowercase() == tag.to_lowercase())
+    }
+
+    /* Error page for works and users hidden by the policy */
+    pub fn blocked() -> ApiError {
+        ApiError::External(451, "This content is not available on this instance".into())
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<pico_args::Error> for ConfigError {
+    fn from(error: pico_args::Error) -> Self {
+        Self(error.to_string())
+    }
+}
+
+impl Config {
+    /* Loads the config file given by --config or REAPIXA_CONFIG, flags take precedence */
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let mut pargs = pico_args::Arguments::from_env();
+
+        let path: Option<String> = match pargs.opt_value_from_str("--config")? {
+            Some(path) => Some(path),
+            None => std::env::var(format!("{ENV_PREFIX}_CONFIG")).ok(),
+        };
+        let mut config = Self::load(path.as_deref())?;
+
+        if let Some(bind) = pargs.opt_value_from_str("--bind")? {
+            config.server.bind = bind;
+        }
+        if let Some(port) = pargs.opt_value_from_str("--port")? {
+            config.server.port = port;
+        }
+        if let Some(host) = pargs.opt_value_from_str("--host")? {
+            config.server.host = Some(host);
+        }
+        let cookies: Vec<String> = pargs.values_from_str("--cookie")?;
+        if !cookies.is_empty() {
+            config.pixiv.cookies = cookies;
+        }
+        if let Some(size) = pargs.opt_value_from_str("--cache-size")? {
+            config.cache.size = size;
+        }
+        if let Some(directory) = pargs.opt_value_from_str("--image-cache")? {
+            config.cache.image_dir = Some(directory);
+        }
+        if let Some(size) = pargs.opt_value_from_str("--image-cache-size")? {
+            config.cache.image_size = size;
+        }
+
+        let remaining = pargs.finish();
+        if !remaining.is_empty() {
+            return Err(ConfigError(format!("Unknown arguments: {remaining:?}")));
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /* Defaults, overridden by the config file, overridden by the environment */
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let mut table = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError(format!("Can't read config file {path}: {e}")))?;
+                content
+                    .parse::<toml::Table>()
+                    .map_err(|e| ConfigError(format!("Invalid config file {path}: {e}")))?
+            }
+            None => toml::Table::new(),
+        };
+
+        let schema = serde_json::to_value(Config::default())
+            .map_err(|e| ConfigError(e.to_string()))?;
+        apply_env(&mut table, &schema, ENV_PREFIX, &|name| std::env::var(name).ok())?;
+
+        table
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError(format!("Invalid configuration: {}", e.message())))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.bind.parse::<IpAddr>().is_err() {
+            return Err(ConfigError(format!(
+                "server.bind: \"{}\" is not an IP address",
+                self.server.bind
+            )));
+        }
+        if let Some(host) = &self.server.host {
+            if !host.starts_with("http://") && !host.starts_with("https://") {
+                return Err(ConfigError(format!(
+                    "server.host: \"{host}\" has to start with http:// or https://"
+                )));
+            }
+        }
+        if self.server.tls_connections == 0 {
+            return Err(ConfigError(
+                "server.tls_connections: has to be positive".into(),
+            ));
+        }
+        if self.server.workers == 0 {
+            return Err(ConfigError("server.workers: has to be positive".into()));
+        }
+        if self.server.tls_cert.is_some() != self.server.tls_key.is_some() {
+            return Err(ConfigError(
+                "server.tls_cert, server.tls_key: have to be set together".into(),
+            ));
+        }
+        if self.server.unix_socket.is_some() && self.server.tls_cert.is_some() {
+            return Err(ConfigError(
+                "server.unix_socket: can't be combined with TLS, the reverse proxy terminates it"
+                    .into(),
+            ));
+        }
+        if self.server.unix_socket_mode.is_some_and(|mode| mode > 0o777) {
+            return Err(ConfigError(
+                "server.unix_socket_mode: has to be a permission like 0o660".into(),
+            ));
+        }
+        for cookie in &self.pixiv.cookies {
+            if !cookie.contains('=') || cookie.contains(['\r', '\n']) {
+                return Err(ConfigError(
+                    "pixiv.cookies: cookies have to look like PHPSESSID=...".into(),
+                ));
+            }
+        }
+        if self.pixiv.cookie_cooldown == 0 {
+            return Err(ConfigError(
+                "pixiv.cookie_cooldown: has to be positive".into(),
+            ));
+        }
+        if self.retry.attempts > retry::MAX_ATTEMPTS {
+            return Err(ConfigError(format!(
+                "retry.attempts: can be at most {}",
+                retry::MAX_ATTEMPTS
+            )));
+        }
+        if u128::from(self.retry.backoff_ms) > retry::MAX_BACKOFF.as_millis() {
+            return Err(ConfigError(format!(
+                "retry.backoff_ms: can be at most {}",
+                retry::MAX_BACKOFF.as_millis()
+            )));
+        }
+        let timeouts = &self.timeouts;
+        if [timeouts.connect, timeouts.read, timeouts.image_connect, timeouts.image_read]
+            .contains(&0)
+        {
+            return Err(ConfigError("timeouts: timeouts have to be positive".into()));
+        }
+        let limits = &self.limits;
+        if [limits.json_size, limits.ugoira_archive, limits.ugoira_output].contains(&0)
+            || limits.ugoira_frames == 0
+        {
+            return Err(ConfigError("limits: limits have to be positive".into()));
+        }
+        if self.instance.robots_tag.contains(['\r', '\n']) {
+            return Err(ConfigError(
+                "instance.robots_tag: has to be a single line".into(),
+            ));
+        }
+        if self.log.level.parse::<log::LevelFilter>().is_err() {
+            return Err(ConfigError(format!(
+                "log.level: \"{}\" is not one of off, error, warn, info, debug, trace",
+                self.log.level
+            )));
+        }
+        if self
+            .security
+            .content_security_policy
+            .contains("frame-ancestors")
+        {
+            return Err(ConfigError(
+                "security.content_security_policy: set frame-ancestors with security.frame_ancestors"
+                    .into(),
+            ));
+        }
+        self.proxy.for_api()?;
+        self.proxy.for_images()?;
+        if self.cache.image_dir.is_some() && self.cache.image_size == 0 {
+            return Err(ConfigError("cache.image_size: has to be positive".into()));
+        }
+        Ok(())
+    }
+
+    /* Public URL of this instance */
+    pub fn host(&self) -> String {
+        self.server.host.clone().unwrap_or_else(|| {
+            let scheme = if self.server.tls_cert.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            format!("{scheme}://localhost:{}", self.server.port)
+        })
+    }
+}
+
+/* Walks all known keys and overlays matching environment variables */
+fn apply_env(
+    table: &mut toml::Table,
+    schema: &serde_json::Value,
+    prefix: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    let Some(keys) = schema.as_object() else {
+        return Ok(());
+    };
+
+    for (key, schema) in keys {
+        let name = format!("{prefix}_{}", key.to_uppercase());
+        if schema.is_object() {
+            let section = table
+                .entry(key.clone())
+                .or_insert_with(|| toml::Table::new().into());
+            let Some(section) = section.as_table_mut() else {
+                return Err(ConfigError(format!("{key}: expected a table")));
+            };
+            apply_env(section, schema, &name, env)?;
+        } else if let Some(value) = env(&name) {
+            table.insert(key.clone(), parse_env_value(&value, schema.is_array()));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_env_value(value: &str, is_array: bool) -> toml::Value {
+    /* Numbers, booleans and TOML arrays keep their type, everything else is a string */
+    if let Ok(table) = format!("value = {value}").parse::<toml::Table>() {
+        if let Some(value) = table.get("value") {
+            if !is_array || value.is_array() {
+                return value.clone();
+            }
+        }
+    }
+
+    if is_array {
+        toml::Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.into()))
+                .collect(),
+        )
+    } else {
+        toml::Value::String(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let mut table = file.parse::<toml::Table>().unwrap();
+        let schema = serde_json::to_value(Config::default()).unwrap();
+        let env = |name: &str| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+        apply_env(&mut table, &schema, ENV_PREFIX, &env)?;
+        table
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError(e.message().into()))
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let config = load(
+            "[server]\nport = 8080\nhost = \"https://example.org\"\n[cache.ttl]\nranking = 1",
+            &[
+                ("REAPIXA_SERVER_PORT", "9000"),
+                ("REAPIXA_CACHE_TTL_SEARCH", "2"),
+                ("REAPIXA_CACHE_IMAGE_DIR", "/var/cache/reapixa"),
+                ("REAPIXA_PIXIV_COOKIES", "PHPSESSID=a, PHPSESSID=b"),
+                ("REAPIXA_ROUTES_SKETCH", "false"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.host.as_deref(), Some("https://example.org"));
+        assert_eq!(config.cache.ttl.ranking, 1);
+        assert_eq!(config.cache.ttl.search, 2);
+        assert_eq!(config.cache.image_dir, Some("/var/cache/reapixa".into()));
+        assert!(!config.cache.allow_bypass);
+        assert_eq!(config.pixiv.cookies, ["PHPSESSID=a", "PHPSESSID=b"]);
+        assert!(!config.routes.sketch);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(load("[server]\nprot = 1", &[]).is_err());
+        assert!(load("", &[("REAPIXA_SERVER_PORT", "http")]).is_err());
+
+        let config = load("[server]\nbind = \"localhost\"", &[]).unwrap();
+        assert!(config.validate().is_err());
+        let config = load("[server]\nhost = \"example.org\"", &[]).unwrap();
+        assert!(config.validate().is_err());
+        let config = load("[server]\nworkers = 0", &[]).unwrap();
+        assert!(config.validate().is_err());
+        let config = load("[retry]\nbackoff_ms = 9223372036854775807", &[]).unwrap();
+        assert!(config.validate().is_err());
+        let config = load("[retry]\nattempts = 4000000000", &[]).unwrap();
+        assert!(config.validate().is_err());
+        let config = load("[server]\ntls_cert = \"cert.pem\"", &[]).unwrap();
+        assert!(config.validate().is_err());
+        let config = load("[server]\nunix_socket = \"/run/reapixa.sock\"", &[]).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn disabled_route_groups() {
+        let routes = RouteConfig {
+            sketch: false,
+            imageproxy: false,
+            ..Default::default()
+        };
+        assert!(routes.allows("/"));
+        assert!(routes.allows("/artworks/1"));
+        assert!(!routes.allows("/sketch/tags/a"));
+        assert!(!routes.allows("/en/sketch"));
+        assert!(!routes.allows("/imageproxy/img-master/a.jpg"));
+    }
+
+    #[test]
+    fn jump_hosts_match_subdomains() {
+        let jump = JumpConfig {
+            allow: vec!["pixiv.me".into()],
+            deny: vec![".example.org".into()],
+        };
+        assert!(jump.allows("pixiv.me"));
+        assert!(jump.allows("www.pixiv.me"));
+        assert!(!jump.allows("notpixiv.me"));
+        assert!(jump.denies("cdn.Example.org"));
+        assert!(!jump.denies("example.org.evil"));
+    }
+
+    #[test]
+    fn content_policy_hides_works() {
+        struct Illust(u64, u32, bool, &'static [&'static str]);
+        impl Work for Illust {
+            fn user_id(&self) -> u64 {
+                self.0
+            }
+            fn x_restrict(&self) -> u32 {
+                self.1
+            }
+            fn is_ai(&self) -> bool {
+                self.2
+            }
+            fn tags(&self) -> impl Iterator<Item = &str> {
+                self.3.iter().copied()
+            }
+        }
+
+        let policy = ContentPolicy {
+            safe_only: true,
+            hide_ai: true,
+            blocked_users: vec![7],
+            blocked_tags: vec!["Gore".into()],
+        };
+        assert!(!policy.hides(&Illust(1, 0, false, &["landscape"])));
+        assert!(policy.hides(&Illust(1, 2, false, &[])));
+        assert!(policy.hides(&Illust(1, 0, true, &[])));
+        assert!(policy.hides(&Illust(7, 0, false, &[])));
+        assert!(policy.hides(&Illust(1, 0, false, &["landscape", "gore"])));
+        assert!(!ContentPolicy::default().hides(&Illust(7, 1, true, &["gore"])));
+        assert!(ContentPolicy::default().hides_nothing());
+        assert!(!policy.hides_nothing());
+    }
+}
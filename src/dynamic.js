@@ -30,3 +30,11 @@ function inject(element, after = true, parent = false) {
     element.remove()
     insert(holder, '<div class="spinner"></div>')
 }
+
+/* Buttons carrying an endpoint load it in place, see inject */
+document.addEventListener('click', function(event) {
+    let button = event.target.closest('button[endpoint]')
+    if (button) {
+        inject(button, button.dataset.prepend === undefined, button.dataset.parent !== undefined)
+    }
+})
@@ -0,0 +1,409 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/* Used when upstream sends neither max-age nor Last-Modified */
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_HEURISTIC_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+pub struct ImageMeta {
+    pub url: String,
+    pub content_type: Option<String>,
+    pub last_modified: Option<String>,
+    pub etag: Option<String>,
+    pub cache_control: Option<String>,
+    /* Unix timestamp after which the image has to be revalidated */
+    pub expires: u64,
+}
+
+impl ImageMeta {
+    /* Returns None if upstream forbids storing the response */
+    pub fn from_headers(url: &str, headers: &ureq::http::HeaderMap) -> Option<Self> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        let mut meta = Self {
+            url: url.to_owned(),
+            content_type: header("content-type"),
+            last_modified: header("last-modified"),
+            etag: header("etag"),
+            cache_control: header("cache-control"),
+            expires: 0,
+        };
+        meta.expires = unix_time(SystemTime::now() + meta.lifetime()?);
+
+        Some(meta)
+    }
+
+    /* Freshness lifetime as described by upstream */
+    fn lifetime(&self) -> Option<Duration> {
+        let mut max_age = None;
+        for directive in self.cache_control.iter().flat_map(|cc| cc.split(',')) {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", value)) if max_age.is_none() => {
+                    max_age = value.trim_matches('"').parse().ok();
+                }
+                Some(("s-maxage", value)) => {
+                    max_age = value.trim_matches('"').parse().ok();
+                }
+                None if directive == "no-store" || directive == "private" => return None,
+                None if directive == "no-cache" => return Some(Duration::ZERO),
+                _ => {}
+            }
+        }
+        if let Some(max_age) = max_age {
+            return Some(Duration::from_secs(max_age));
+        }
+
+        /* Heuristic freshness: a tenth of the time since the last modification */
+        let last_modified = self
+            .last_modified
+            .as_deref()
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok());
+        match last_modified {
+            Some(date) => {
+                let age = chrono::Utc::now().signed_duration_since(date);
+                let lifetime = age.to_std().unwrap_or_default() / 10;
+                Some(lifetime.min(MAX_HEURISTIC_LIFETIME))
+            }
+            None => Some(DEFAULT_LIFETIME),
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        unix_time(SystemTime::now()) < self.expires
+    }
+
+    /* Extends the lifetime after upstream confirmed the image is unchanged */
+    pub fn revalidated(&mut self, headers: &ureq::http::HeaderMap) {
+        if let Some(cache_control) = headers.get("cache-control") {
+            self.cache_control = cache_control.to_str().ok().map(str::to_owned);
+        }
+        let lifetime = self.lifetime().unwrap_or_default();
+        self.expires = unix_time(SystemTime::now() + lifetime);
+    }
+}
+
+pub struct CachedImage {
+    pub meta: ImageMeta,
+    pub file: File,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, (u64, u64)>,
+    /* Least recently used entries come first */
+    recency: BTreeMap<u64, String>,
+    size: u64,
+    tick: u64,
+}
+
+impl Index {
+    fn touch(&mut self, key: &str) -> bool {
+        let Some((_, last_used)) = self.entries.get_mut(key) else {
+            return false;
+        };
+        if let Some(key) = self.recency.remove(last_used) {
+            self.tick += 1;
+            *last_used = self.tick;
+            self.recency.insert(self.tick, key);
+        }
+        true
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        self.remove(&key);
+        self.tick += 1;
+        self.size += size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (size, self.tick));
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Some((size, last_used)) = self.entries.remove(key) else {
+            return false;
+        };
+        self.recency.remove(&last_used);
+        self.size -= size;
+        true
+    }
+}
+
+struct Inner {
+    directory: PathBuf,
+    capacity: u64,
+    index: Mutex<Index>,
+    temporary: AtomicU64,
+}
+
+/* On-disk LRU cache for proxied images */
+#[derive(Clone)]
+pub struct ImageCache {
+    inner: Arc<Inner>,
+}
+
+impl ImageCache {
+    pub fn open(directory: impl Into<PathBuf>, capacity: u64) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        /* Rebuild the index, oldest files are evicted first */
+        let mut found = vec![];
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                }
+                Some("data") => {
+                    let Some(key) = path.file_stem().and_then(|s| s.to_str()) else {
+                        continue;
+                    };
+                    let metadata = entry.metadata()?;
+                    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+                    found.push((modified, key.to_owned(), metadata.len()));
+                }
+                _ => {}
+            }
+        }
+        found.sort_unstable();
+
+        let cache = Self {
+            inner: Arc::new(Inner {
+                directory,
+                capacity,
+                index: Mutex::default(),
+                temporary: AtomicU64::new(0),
+            }),
+        };
+        for (_, key, size) in found {
+            cache.inner.index.lock().unwrap().insert(key, size);
+        }
+        cache.inner.evict(0);
+
+        Ok(cache)
+    }
+
+    pub fn lookup(&self, url: &str) -> Option<CachedImage> {
+        let key = cache_key(url);
+        if !self.inner.index.lock().unwrap().touch(&key) {
+            return None;
+        }
+
+        let cached = (|| {
+            let meta = fs::read(self.inner.path(&key, "meta")).ok()?;
+            let meta: ImageMeta = serde_json::from_slice(&meta).ok()?;
+            let file = File::open(self.inner.path(&key, "data")).ok()?;
+            (meta.url == url).then_some(CachedImage { meta, file })
+        })();
+        if cached.is_none() {
+            self.inner.remove(&key);
+        }
+
+        cached
+    }
+
+    pub fn update(&self, meta: &ImageMeta) {
+        let _ = self.inner.write_meta(&cache_key(&meta.url), meta);
+    }
+
+    /* Stores everything read from `reader` once it has been read to the end */
+    pub fn store<R: Read>(&self, meta: ImageMeta, reader: R) -> CacheWriter<R> {
+        let key = cache_key(&meta.url);
+        let id = self.inner.temporary.fetch_add(1, Ordering::Relaxed);
+        let temporary = self.inner.path(&key, &format!("{id}.tmp"));
+        let file = File::create(&temporary).ok();
+
+        CacheWriter {
+            reader,
+            inner: self.inner.clone(),
+            key,
+            meta,
+            temporary,
+            file,
+            written: 0,
+        }
+    }
+}
+
+impl Inner {
+    fn path(&self, key: &str, extension: &str) -> PathBuf {
+        self.directory.join(format!("{key}.{extension}"))
+    }
+
+    fn write_meta(&self, key: &str, meta: &ImageMeta) -> io::Result<()> {
+        let id = self.temporary.fetch_add(1, Ordering::Relaxed);
+        let temporary = self.path(key, &format!("{id}.tmp"));
+        fs::write(&temporary, serde_json::to_vec(meta)?)?;
+        fs::rename(temporary, self.path(key, "meta"))
+    }
+
+    fn remove(&self, key: &str) {
+        self.index.lock().unwrap().remove(key);
+        let _ = fs::remove_file(self.path(key, "data"));
+        let _ = fs::remove_file(self.path(key, "meta"));
+    }
+
+    /* Drops the least recently used images until `incoming` bytes fit */
+    fn evict(&self, incoming: u64) {
+        loop {
+            let key = {
+                let mut index = self.index.lock().unwrap();
+                if index.size + incoming <= self.capacity {
+                    return;
+                }
+                let Some((_, key)) = index.recency.pop_first() else {
+                    return;
+                };
+                if let Some((size, _)) = index.entries.remove(&key) {
+                    index.size -= size;
+                }
+                key
+            };
+            let _ = fs::remove_file(self.path(&key, "data"));
+            let _ = fs::remove_file(self.path(&key, "meta"));
+        }
+    }
+
+    fn commit(&self, key: String, meta: &ImageMeta, temporary: &Path, size: u64) -> io::Result<()> {
+        if size > self.capacity {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        self.evict(size);
+        fs::rename(temporary, self.path(&key, "data"))?;
+        self.write_meta(&key, meta)?;
+        self.index.lock().unwrap().insert(key, size);
+        Ok(())
+    }
+}
+
+pub struct CacheWriter<R> {
+    reader: R,
+    inner: Arc<Inner>,
+    key: String,
+    meta: ImageMeta,
+    temporary: PathBuf,
+    file: Option<File>,
+    written: u64,
+}
+
+impl<R: Read> Read for CacheWriter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+
+        if let Some(file) = &mut self.file {
+            if read == 0 {
+                let _ = file.flush();
+                self.file = None;
+                let key = std::mem::take(&mut self.key);
+                if self
+                    .inner
+                    .commit(key, &self.meta, &self.temporary, self.written)
+                    .is_err()
+                {
+                    let _ = fs::remove_file(&self.temporary);
+                }
+            } else if file.write_all(&buf[..read]).is_ok() {
+                self.written += read as u64;
+            } else {
+                self.file = None;
+                let _ = fs::remove_file(&self.temporary);
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+impl<R> Drop for CacheWriter<R> {
+    fn drop(&mut self) {
+        /* The client went away before the image was complete */
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temporary);
+        }
+    }
+}
+
+/* FNV-1a, stable across builds so existing cache directories stay valid */
+fn cache_key(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(url: &str, cache_control: Option<&str>) -> ImageMeta {
+        ImageMeta {
+            url: url.to_owned(),
+            content_type: None,
+            last_modified: None,
+            etag: None,
+            cache_control: cache_control.map(str::to_owned),
+            expires: u64::MAX,
+        }
+    }
+
+    #[test]
+    fn cache_control_lifetime() {
+        let lifetime = |cc| meta("", Some(cc)).lifetime();
+        assert_eq!(lifetime("max-age=31536000"), Some(Duration::from_secs(31536000)));
+        assert_eq!(lifetime("public, s-maxage=60, max-age=10"), Some(Duration::from_secs(60)));
+        assert_eq!(lifetime("no-cache"), Some(Duration::ZERO));
+        assert_eq!(lifetime("private, max-age=60"), None);
+        assert_eq!(lifetime("no-store"), None);
+        assert_eq!(meta("", None).lifetime(), Some(DEFAULT_LIFETIME));
+    }
+
+    #[test]
+    fn store_and_evict() {
+        let directory = std::env::temp_dir().join(format!("reapixa-test-{}", std::process::id()));
+        let cache = ImageCache::open(&directory, 10).unwrap();
+
+        for url in ["a", "b"] {
+            let mut writer = cache.store(meta(url, None), &[0u8; 6][..]);
+            io::copy(&mut writer, &mut io::sink()).unwrap();
+        }
+
+        /* "a" had to make room for "b" */
+        assert!(cache.lookup("a").is_none());
+        let mut cached = cache.lookup("b").unwrap();
+        let mut data = vec![];
+        cached.file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [0u8; 6]);
+
+        /* Incomplete downloads are never committed */
+        let mut writer = cache.store(meta("c", None), &[0u8; 4][..]);
+        writer.read_exact(&mut [0u8; 2]).unwrap();
+        drop(writer);
+        assert!(cache.lookup("c").is_none());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
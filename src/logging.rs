@@ -0,0 +1,203 @@
+use std::{
+    io::Write,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+use crate::config::LogConfig;
+
+/* Target of the upstream URL lines, switched on by log.upstream_urls */
+pub const UPSTREAM: &str = "reapixa::upstream";
+const ACCESS: &str = "reapixa::access";
+
+const REDACTED: &str = "[redacted]";
+/* Values following these are cut at the next delimiter */
+const SECRET_PARAMETERS: &[&str] = &["phpsessid=", "device_token=", "token=", "password="];
+/* Header values are cut at the end of the line */
+const SECRET_HEADERS: &[&str] = &["cookie: ", "authorization: "];
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    level: LevelFilter,
+    json: bool,
+    access: bool,
+    upstream_urls: bool,
+    /* Exact strings that never make it into the log, e.g. configured cookies */
+    secrets: RwLock<Vec<String>>,
+}
+
+impl Logger {
+    fn write(&self, level: Level, target: &str, message: &str, fields: serde_json::Value) {
+        let message = redact(message, &self.secrets.read().unwrap());
+        let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        let line = if self.json {
+            let mut line = json!({
+                "time": time,
+                "level": level.as_str(),
+                "target": target,
+                "message": message,
+            });
+            if let (Some(line), serde_json::Value::Object(fields)) = (line.as_object_mut(), fields)
+            {
+                line.extend(fields);
+            }
+            line.to_string()
+        } else {
+            format!("{time} {level:<5} {target}: {message}")
+        };
+
+        let _ = writeln!(std::io::stderr().lock(), "{line}");
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        if target == UPSTREAM && !self.upstream_urls {
+            return false;
+        }
+
+        /* Dependencies may dump headers at debug level, keep them quiet */
+        let level = if target.starts_with("reapixa") {
+            self.level
+        } else {
+            self.level.min(LevelFilter::Warn)
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let message = record.args().to_string();
+            self.write(
+                record.level(),
+                record.target(),
+                &message,
+                serde_json::Value::Null,
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(config: &LogConfig) {
+    let level = config.level.parse().unwrap_or(LevelFilter::Info);
+    let logger = LOGGER.get_or_init(|| Logger {
+        level,
+        json: config.json,
+        access: config.access,
+        upstream_urls: config.upstream_urls,
+        secrets: RwLock::default(),
+    });
+
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/* Cookie strings are split so their values are caught on their own too */
+pub fn add_secret(secret: &str) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+
+    let mut secrets = logger.secrets.write().unwrap();
+    let values = secret
+        .split(';')
+        .map(|pair| pair.split_once('=').map_or(pair, |(_, value)| value).trim());
+    for value in std::iter::once(secret).chain(values) {
+        if value.len() >= 4 && !secrets.iter().any(|s| s == value) {
+            secrets.push(value.to_owned());
+        }
+    }
+    /* Longer secrets first so they aren't partially replaced by their parts */
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+}
+
+/* One line per handled request */
+pub fn access(method: &str, path: &str, status: u16, duration: Duration) {
+    let Some(logger) = LOGGER.get().filter(|logger| logger.access) else {
+        return;
+    };
+    if logger.level < LevelFilter::Info {
+        return;
+    }
+
+    let milliseconds = duration.as_secs_f64() * 1000.0;
+    let path = redact(path, &logger.secrets.read().unwrap());
+    logger.write(
+        Level::Info,
+        ACCESS,
+        &format!("{method} {path} {status} {milliseconds:.1}ms"),
+        json!({
+            "method": method,
+            "path": path,
+            "status": status,
+            "duration_ms": milliseconds,
+        }),
+    );
+}
+
+fn redact(message: &str, secrets: &[String]) -> String {
+    let mut message = message.to_owned();
+    for secret in secrets {
+        message = message.replace(secret.as_str(), REDACTED);
+    }
+
+    for key in SECRET_PARAMETERS {
+        redact_after(&mut message, key, |c| {
+            matches!(c, ';' | ',' | '&' | '"' | '\'' | '\n') || c.is_whitespace()
+        });
+    }
+    for key in SECRET_HEADERS {
+        redact_after(&mut message, key, |c| matches!(c, '"' | '\n' | '\r'));
+    }
+
+    message
+}
+
+/* Replaces whatever follows `key` (matched case-insensitively) up to `stop` */
+fn redact_after(message: &mut String, key: &str, stop: impl Fn(char) -> bool) {
+    let mut start = 0;
+    while let Some(position) = message[start..].to_ascii_lowercase().find(key) {
+        let from = start + position + key.len();
+        let length = message[from..].find(&stop).unwrap_or(message.len() - from);
+        if length > 0 {
+            message.replace_range(from..from + length, REDACTED);
+        }
+        start = from + if length > 0 { REDACTED.len() } else { 0 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secrets() {
+        let secrets = vec!["12345_abcdef".to_owned()];
+        assert_eq!(
+            redact("Cookies: PHPSESSID=12345_abcdef", &secrets),
+            "Cookies: PHPSESSID=[redacted]"
+        );
+        assert_eq!(
+            redact("session 12345_abcdef retired", &secrets),
+            "session [redacted] retired"
+        );
+        assert_eq!(
+            redact("GET /?phpsessid=999_x&p=2", &[]),
+            "GET /?phpsessid=[redacted]&p=2"
+        );
+        assert_eq!(
+            redact("Cookie: a=b; c=d\nAccept: */*", &[]),
+            "Cookie: [redacted]\nAccept: */*"
+        );
+        assert_eq!(redact("nothing to see", &secrets), "nothing to see");
+    }
+}
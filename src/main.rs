@@ -1,10 +1,20 @@
 mod api;
+mod config;
+mod imagecache;
+mod logging;
+mod metrics;
+mod ratelimit;
 mod render;
 mod routes;
+mod security;
+mod server;
+mod tls;
 mod util;
 
 use api::error::ApiError;
+use config::Config;
 use routes::*;
+use std::time::{Duration, Instant};
 use ureq::{
     http::{self, HeaderValue},
     middleware::MiddlewareNext,
@@ -13,156 +23,233 @@ use ureq::{
 
 const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:103.0) Gecko/20100101 Firefox/103.0";
 
-fn main() -> std::io::Result<()> {
-    let mut pargs = pico_args::Arguments::from_env();
-    let port: u32 = pargs.value_from_str("--port").unwrap_or(8000);
-    let listen_address = format!("http://localhost:{}", port);
-    let host: String = pargs
-        .value_from_str("--host")
-        .unwrap_or_else(|_| listen_address.clone());
-    let cookie: String = match pargs.value_from_str("--cookie") {
-        Ok(cookie) => cookie,
-        Err(_) => {
-            println!("No cookie set. Fetching generic one.");
-            println!("Keep in mind that this will offer very limited functionality.");
-
-            let client = ureq::Agent::new_with_defaults();
-            let res = client
-                .get("https://www.pixiv.net/en/")
-                .call()
-                .expect("Can't contact Pixiv to fetch default header!");
-
-            let cookie = res
-                .headers()
-                .iter()
-                .filter(|h| h.0 == "set-cookie")
-                .filter_map(|h| h.1.to_str().ok())
-                .find(|h| h.starts_with("PHPSESSID"))
-                .expect("No cookie obtained!")
-                .to_owned();
-
-            let length = cookie.find("; ").unwrap();
-            cookie[..length].to_owned()
+fn main() {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Error: {error}");
+            std::process::exit(2);
         }
     };
 
-    println!("Listening on {}", listen_address);
-    println!("Cookies: {}", cookie);
+    logging::init(&config.log);
+    for cookie in &config.pixiv.cookies {
+        logging::add_secret(cookie);
+    }
 
-    /* Build HTTP client */
-    let client = {
-        /* Load tls certificate */
-        let tls_config = ureq::tls::TlsConfig::builder().build();
-
-        /* Add default headers */
-        struct PixivDefaultHeaders {
-            referer: String,
-            cookie: String,
+    /* Outbound proxies */
+    let (api_proxy, image_proxy) = match (config.proxy.for_api(), config.proxy.for_images()) {
+        (Ok(api), Ok(images)) => (api, images),
+        (Err(error), _) | (_, Err(error)) => {
+            log::error!("{error}");
+            std::process::exit(2);
         }
+    };
 
-        impl ureq::middleware::Middleware for PixivDefaultHeaders {
-            fn handle(
-                &self,
-                mut request: http::Request<SendBody>,
-                next: MiddlewareNext,
-            ) -> Result<http::Response<Body>, ureq::Error> {
-                let headers = request.headers_mut();
-                headers.append("Referer", HeaderValue::from_str(&self.referer).unwrap());
-                headers.append("Cookie", HeaderValue::from_str(&self.cookie).unwrap());
-                next.handle(request)
-            }
+    /* Add default headers */
+    struct PixivDefaultHeaders {
+        referer: String,
+    }
+
+    impl ureq::middleware::Middleware for PixivDefaultHeaders {
+        fn handle(
+            &self,
+            mut request: http::Request<SendBody>,
+            next: MiddlewareNext,
+        ) -> Result<http::Response<Body>, ureq::Error> {
+            let headers = request.headers_mut();
+            headers.append("Referer", HeaderValue::from_str(&self.referer).unwrap());
+            next.handle(request)
         }
+    }
 
-        let middleware: PixivDefaultHeaders = PixivDefaultHeaders {
-            referer: "https://pixiv.net/".to_string(),
-            cookie,
-        };
+    let agent_config = |proxy: Option<ureq::Proxy>, connect: u64, read: u64| {
+        /* Load tls certificate */
+        let tls_config = ureq::tls::TlsConfig::builder().build();
 
         /* Build https client */
-        let config = ureq::Agent::config_builder()
+        ureq::Agent::config_builder()
             .tls_config(tls_config)
             .user_agent(USER_AGENT)
-            .middleware(middleware)
+            .timeout_connect(Some(Duration::from_secs(connect)))
+            .timeout_recv_response(Some(Duration::from_secs(read)))
+            .timeout_recv_body(Some(Duration::from_secs(read)))
             .max_redirects(0)
-            .build();
+            .proxy(proxy)
+    };
+
+    /* Build session pool */
+    let cooldown = Duration::from_secs(config.pixiv.cookie_cooldown);
+    let sessions = if !config.pixiv.cookies.is_empty() {
+        log::info!("Using {} configured pixiv sessions", config.pixiv.cookies.len());
+        api::session::SessionPool::new(&config.pixiv.cookies, cooldown)
+    } else {
+        log::warn!("No cookie set. Fetching generic one.");
+        log::warn!("Keep in mind that this will offer very limited functionality.");
+
+        let interval = config.pixiv.guest_refresh;
+        let interval = (interval > 0).then(|| Duration::from_secs(interval));
+        let agent = ureq::Agent::config_builder()
+            .proxy(api_proxy.clone())
+            .build()
+            .new_agent();
+        api::session::SessionPool::guest(agent, cooldown, interval)
+    };
+    let sessions = sessions.unwrap_or_else(|error| {
+        log::error!("Can't set up pixiv sessions: {error}");
+        std::process::exit(1);
+    });
+
+    /* Answer repeated ajax calls from memory before they reach pixiv */
+    let response_cache = (config.cache.size > 0).then(|| {
+        api::cache::ResponseCache::new(config.cache.size * 1024 * 1024, (&config.cache.ttl).into())
+    });
+
+    /* Build HTTP clients */
+    let client = {
+        let timeouts = &config.timeouts;
+        let mut builder = agent_config(api_proxy, timeouts.connect, timeouts.read);
+
+        if let Some(cache) = &response_cache {
+            builder = builder.middleware(cache.clone());
+        }
 
-        ureq::Agent::new_with_config(config)
+        /* Only requests that actually reach pixiv are measured */
+        builder = builder.middleware(metrics::UpstreamMetrics);
+
+        let builder = builder
+            .middleware(PixivDefaultHeaders {
+                referer: "https://pixiv.net/".to_string(),
+            })
+            .middleware(sessions.clone());
+
+        ureq::Agent::new_with_config(builder.build())
+    };
+
+    /* Image hosts only need the referer, not the session */
+    let image_client = {
+        let timeouts = &config.timeouts;
+        let builder = agent_config(image_proxy, timeouts.image_connect, timeouts.image_read)
+            .middleware(metrics::UpstreamMetrics)
+            .middleware(PixivDefaultHeaders {
+                referer: "https://pixiv.net/".to_string(),
+            });
+
+        ureq::Agent::new_with_config(builder.build())
     };
+    api::retry::RetryPolicy::from(&config.retry).install();
+    api::fetch::set_max_json_size(config.limits.json_size * 1024 * 1024);
+
+    /* Open image cache */
+    let image_cache = config.cache.image_dir.as_ref().map(|directory| {
+        imagecache::ImageCache::open(directory, config.cache.image_size * 1024 * 1024)
+            .unwrap_or_else(|error| {
+                log::error!("Can't open image cache {}: {error}", directory.display());
+                std::process::exit(1);
+            })
+    });
 
     /* Build RSS config */
-    let rss_config = routes::rss::RssConfig { host };
+    let rss_config = routes::rss::RssConfig { host: config.host() };
+
+    /* Published on /about and /instance.json */
+    let instance = about::Instance::new(&config);
+
+    /* Build rate limiter */
+    let limiter = config.ratelimit.enabled.then(|| {
+        ratelimit::RateLimiter::new(
+            (&config.ratelimit).into(),
+            config.ratelimit.trust_forwarded_for,
+        )
+    });
+
+    let handle = move |request: &rouille::Request| -> rouille::Response {
+        /* Route groups disabled by the operator */
+        if !config.routes.allows(&request.url()) {
+            return rouille::Response::html(render::error::render_error(404, "Not Found"))
+                .with_status_code(404);
+        }
+
+        if let Some(response) = limiter.as_ref().and_then(|limiter| limiter.check(request)) {
+            return response;
+        }
 
-    let address = format!("0.0.0.0:{}", port);
-    rouille::start_server(&address, move |request| {
         let result = rouille::router!(request,
             /* Front page */
-            (GET) ["/"] => { ranking::ranking(&client, request) },
+            (GET) ["/"] => { ranking::ranking(&client, request, &config.content) },
+            (GET) ["/ranking"] => { ranking::ranking(&client, request, &config.content) },
+            (GET) ["/ranking/calendar"] => { ranking::calendar(response_cache.as_ref(), request, &config.content) },
 
             /* Search */
-            (GET) ["/tags/{tag}", tag: String] => { search::tags(&client, &tag, request) },
-            (GET) ["/tags/{tag}/artworks", tag: String] => { search::tags(&client, &tag, request) },
-            (GET) ["/search"] => { search::query_search(&client, request) },
+            (GET) ["/tags/{tag}", tag: String] => { search::tags(&client, &tag, request, &config.content) },
+            (GET) ["/tags/{tag}/artworks", tag: String] => { search::tags(&client, &tag, request, &config.content) },
+            (GET) ["/search"] => { search::query_search(&client, request, &config.content) },
 
             /* Scrolling image view */
-            (GET) ["/scroll"] => { scroll::scroll(&client, request) },
+            (GET) ["/scroll"] => { scroll::scroll(&client, request, &config.content) },
 
             /* Users */
-            (GET) ["/users/{id}", id: u64] => { users::artworks(&client, id, request) },
-            (GET) ["/users/{id}/artworks", id: u64] => { users::artworks(&client, id, request) },
-            (GET) ["/users/{id}/bookmarks/artworks", id: u64] => { users::bookmarks(&client, id, request) },
+            (GET) ["/users/{id}", id: u64] => { users::artworks(&client, id, request, &config.content) },
+            (GET) ["/users/{id}/artworks", id: u64] => { users::artworks(&client, id, request, &config.content) },
+            (GET) ["/users/{id}/bookmarks/artworks", id: u64] => { users::bookmarks(&client, id, request, &config.content) },
 
             /* Artworks */
-            (GET) ["/artworks/{id}", id: u64] => { artworks::artwork(&client, id) },
+            (GET) ["/artworks/{id}", id: u64] => { artworks::artwork(&client, id, &config.content) },
 
             /* Comments */
-            (GET) ["/comments/{id}", id: u64] => { comments::comments(&client, id, request) },
+            (GET) ["/comments/{id}", id: u64] => { comments::comments(&client, id, request, &config.content) },
             (GET) ["/replies/{id}", id: u64] => { comments::replies(&client, id, request) },
 
             /* Jump pads */
-            (GET) ["/jump.php"] => { redirect::jump(request) },
+            (GET) ["/jump.php"] => { redirect::jump(request, &config.jump) },
             (GET) ["/member_illust.php"] => { redirect::legacy_illust(request) },
             (GET) ["/fanbox/creator/{id}", id: u64] => { redirect::fanbox(&client, id) },
 
             /* Sketch */
-            (GET) ["/sketch"] => { sketch::sketch_public(&client) },
-            (GET) ["/sketch/tags/{tag}", tag: String] => { sketch::sketch_tags(&client, &tag) },
-            (GET) ["/sketch/users/{id}", id: u64] => { sketch::sketch_user(&client, id) },
-            (GET) ["/sketch/items/{id}", id: u64] => { sketch::sketch_item(&client, id) },
-            (GET) ["/sketch/lives"] => { sketch::sketch_lives(&client) },
-            (GET) ["/sketch/impressions/{id}", id: u64] => { sketch::sketch_user(&client, id) },
+            (GET) ["/sketch"] => { sketch::sketch_public(&client, &config.content) },
+            (GET) ["/sketch/tags/{tag}", tag: String] => { sketch::sketch_tags(&client, &tag, &config.content) },
+            (GET) ["/sketch/users/{id}", id: u64] => { sketch::sketch_user(&client, id, &config.content) },
+            (GET) ["/sketch/items/{id}", id: u64] => { sketch::sketch_item(&client, id, &config.content) },
+            (GET) ["/sketch/lives"] => { sketch::sketch_lives(&client, &config.content) },
+            (GET) ["/sketch/impressions/{id}", id: u64] => { sketch::sketch_impressions(&client, id, &config.content) },
 
             /* Ugoira */
-            (GET) ["/ugoira/{id}", id: u64] => { ugoira::ugoira(&client, id) },
+            (GET) ["/ugoira/{id}", id: u64] => { ugoira::ugoira(&client, &image_client, id, &config.limits, &config.content) },
 
             /* RSS */
-            (GET) ["/rss"] => { rss::rss(&client, request, &rss_config) },
+            (GET) ["/rss"] => { rss::rss(&client, request, &rss_config, &config.content) },
 
             /* Image proxy */
-            (GET) ["/stamp/{id}", id: u32] => { imageproxy::stamp(&client, id, request) },
+            (GET) ["/stamp/{id}", id: u32] => { imageproxy::stamp(&image_client, id, request, image_cache.as_ref()) },
 
             /* Stylesheet */
             (GET) ["/stylesheet.css"] => { Ok(css::style_sheet()) },
             (GET) ["/favicon.ico"] => { Ok(favicon::favicon()) },
 
             /* Settings */
-            (GET) ["/settings"] => { Ok(settings::index(request)) },
+            (GET) ["/settings"] => { Ok(settings::index(request, &config.content)) },
+            (POST) ["/settings/r18"] => { Ok(settings::r18_set(request)) },
             (POST) ["/settings/blocked/add"] => { Ok(settings::blocked_users_add(request)) },
             (POST) ["/settings/blocked/del"] => { Ok(settings::blocked_users_del(request)) },
 
             /* About */
-            (GET) ["/about"] => { Ok(about::about()) },
+            (GET) ["/about"] => { Ok(about::about(&instance)) },
+            (GET) ["/instance.json"] => { Ok(about::instance_json(&instance)) },
+            (GET) ["/robots.txt"] => { Ok(about::robots(&config.instance.robots_txt)) },
+            (GET) ["/health"] => { Ok(health::health(&sessions)) },
+            (GET) ["/metrics"] => { Ok(routes::metrics::metrics(&sessions)) },
 
             _ => {
                 let path = request.url();
                 if let Some(path) = path.strip_prefix("/en") {
                     Ok(rouille::Response::redirect_301(path.to_owned()))
-                } else if let Some(response) = imageproxy::imageproxy(&client, &path, request) {
+                } else if let Some(response) = imageproxy::imageproxy(&image_client, &path, request, image_cache.as_ref()) {
                     response
-                } else if let Some(response) = imageproxy::s_imageproxy(&client, &path, request) {
+                } else if let Some(response) = imageproxy::s_imageproxy(&image_client, &path, request, image_cache.as_ref()) {
                     response
-                } else if let Some(response) = imageproxy::spix_imageproxy(&client, &path, request) {
+                } else if let Some(response) = imageproxy::spix_imageproxy(&image_client, &path, request, image_cache.as_ref()) {
                     response
-                } else if let Some(response) = imageproxy::spxi_imageproxy(&client, &path, request) {
+                } else if let Some(response) = imageproxy::spxi_imageproxy(&image_client, &path, request, image_cache.as_ref()) {
                     response
                 } else {
                     Err(ApiError::External(404, "Not Found".into()))
@@ -173,14 +260,45 @@ fn main() -> std::io::Result<()> {
         match result {
             Ok(response) => response,
             Err(error) => {
+                log::warn!("{} failed: {error}", request.url());
+                let status = error.status_code();
+                let retry_after = match error {
+                    ApiError::RateLimited(Some(seconds)) => Some(seconds),
+                    _ => None,
+                };
                 let page = match error {
                     ApiError::Internal(message) => render::error::render_error(500, &message),
                     ApiError::External(code, message) => {
                         render::error::render_error(code, &message)
                     }
+                    ApiError::RateLimited(_) => {
+                        render::error::render_error(429, &error.to_string())
+                    }
                 };
-                rouille::Response::html(page)
+                let response = rouille::Response::html(page).with_status_code(status);
+                match retry_after {
+                    Some(seconds) => response.with_unique_header("Retry-After", seconds.to_string()),
+                    None => response,
+                }
             }
         }
-    })
+    };
+
+    let result = server::serve(&config.server, move |request| {
+        let start = Instant::now();
+        security::begin_request();
+        api::cache::begin_request(request, config.cache.allow_bypass);
+        let mut response = security::apply(handle(request), &config.security);
+        if !config.instance.robots_tag.is_empty() {
+            response = response.with_unique_header("X-Robots-Tag", config.instance.robots_tag.clone());
+        }
+        let duration = start.elapsed();
+        metrics::record_request(&request.url(), response.status_code, duration);
+        logging::access(request.method(), &request.url(), response.status_code, duration);
+        response
+    });
+    if let Err(error) = result {
+        log::error!("Can't start the server: {error}");
+        std::process::exit(1);
+    }
 }
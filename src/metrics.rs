@@ -0,0 +1,445 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use ureq::{
+    http,
+    middleware::{Middleware, MiddlewareNext},
+    Body, SendBody,
+};
+
+/* Upper bounds in seconds, shared by all latency histograms */
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /* Observations per bucket, the last slot counts everything above the largest bound */
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += seconds;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        cumulative += self.counts[BUCKETS.len()];
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {cumulative}"
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {cumulative}");
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: BTreeMap<(&'static str, u16), u64>,
+    request_durations: BTreeMap<&'static str, Histogram>,
+    /* Status 0 stands for transport errors */
+    upstream: BTreeMap<(&'static str, u16), u64>,
+    upstream_durations: BTreeMap<&'static str, Histogram>,
+    ugoira: Histogram,
+    cache: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+#[derive(Default)]
+struct Metrics {
+    registry: Mutex<Registry>,
+    image_bytes_upstream: AtomicU64,
+    image_bytes_cached: AtomicU64,
+}
+
+pub fn record_request(path: &str, status: u16, duration: Duration) {
+    let route = route(path);
+    let mut registry = METRICS.registry.lock().unwrap();
+    *registry.requests.entry((route, status)).or_default() += 1;
+    registry
+        .request_durations
+        .entry(route)
+        .or_default()
+        .observe(duration);
+}
+
+fn record_upstream(endpoint: &'static str, status: u16, duration: Duration) {
+    let mut registry = METRICS.registry.lock().unwrap();
+    registry
+        .upstream_durations
+        .entry(endpoint)
+        .or_default()
+        .observe(duration);
+    *registry.upstream.entry((endpoint, status)).or_default() += 1;
+}
+
+#[cfg_attr(not(feature = "ugoira"), allow(dead_code))]
+pub fn record_ugoira(duration: Duration) {
+    METRICS.registry.lock().unwrap().ugoira.observe(duration);
+}
+
+/* `cache` is "response" or "image" */
+pub fn record_cache(cache: &'static str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    *METRICS
+        .registry
+        .lock()
+        .unwrap()
+        .cache
+        .entry((cache, result))
+        .or_default() += 1;
+}
+
+pub fn record_cached_image_bytes(bytes: u64) {
+    METRICS
+        .image_bytes_cached
+        .fetch_add(bytes, Ordering::Relaxed);
+}
+
+/* Counts image bytes as they are streamed to the client */
+pub struct CountingReader<R>(pub R);
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        METRICS
+            .image_bytes_upstream
+            .fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/* Gauges that are read at scrape time */
+pub struct Gauge<'a> {
+    pub name: &'a str,
+    pub help: &'a str,
+    pub value: f64,
+}
+
+/* Prometheus text exposition format */
+pub fn export(gauges: &[Gauge]) -> String {
+    let registry = METRICS.registry.lock().unwrap();
+    let mut out = String::new();
+
+    let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+    };
+
+    header(
+        &mut out,
+        "reapixa_requests_total",
+        "counter",
+        "Requests handled per route and status.",
+    );
+    for ((route, status), count) in &registry.requests {
+        let _ = writeln!(
+            out,
+            "reapixa_requests_total{{route=\"{route}\",status=\"{status}\"}} {count}"
+        );
+    }
+
+    header(
+        &mut out,
+        "reapixa_request_duration_seconds",
+        "histogram",
+        "Time until the response headers were ready, per route.",
+    );
+    for (route, histogram) in &registry.request_durations {
+        histogram.write(
+            &mut out,
+            "reapixa_request_duration_seconds",
+            &format!("route=\"{route}\""),
+        );
+    }
+
+    header(
+        &mut out,
+        "reapixa_upstream_requests_total",
+        "counter",
+        "Requests sent to pixiv per endpoint and status, 0 means the connection failed.",
+    );
+    for ((endpoint, status), count) in &registry.upstream {
+        let _ = writeln!(
+            out,
+            "reapixa_upstream_requests_total{{endpoint=\"{endpoint}\",status=\"{status}\"}} {count}"
+        );
+    }
+
+    header(
+        &mut out,
+        "reapixa_upstream_request_duration_seconds",
+        "histogram",
+        "Time until pixiv's response headers arrived, per endpoint.",
+    );
+    for (endpoint, histogram) in &registry.upstream_durations {
+        histogram.write(
+            &mut out,
+            "reapixa_upstream_request_duration_seconds",
+            &format!("endpoint=\"{endpoint}\""),
+        );
+    }
+
+    header(
+        &mut out,
+        "reapixa_image_proxy_bytes_total",
+        "counter",
+        "Image bytes sent to clients, by where they came from.",
+    );
+    for (source, bytes) in [
+        ("upstream", &METRICS.image_bytes_upstream),
+        ("cache", &METRICS.image_bytes_cached),
+    ] {
+        let _ = writeln!(
+            out,
+            "reapixa_image_proxy_bytes_total{{source=\"{source}\"}} {}",
+            bytes.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "reapixa_ugoira_encode_duration_seconds",
+        "histogram",
+        "Time spent re-encoding ugoira.",
+    );
+    registry
+        .ugoira
+        .write(&mut out, "reapixa_ugoira_encode_duration_seconds", "");
+
+    header(
+        &mut out,
+        "reapixa_cache_requests_total",
+        "counter",
+        "Cache lookups by cache and result.",
+    );
+    for ((cache, result), count) in &registry.cache {
+        let _ = writeln!(
+            out,
+            "reapixa_cache_requests_total{{cache=\"{cache}\",result=\"{result}\"}} {count}"
+        );
+    }
+
+    for gauge in gauges {
+        header(&mut out, gauge.name, "gauge", gauge.help);
+        let _ = writeln!(out, "{} {}", gauge.name, gauge.value);
+    }
+
+    out
+}
+
+/* Route pattern from the router table, so IDs and tags don't become labels */
+fn route(path: &str) -> &'static str {
+    let path = path.split('?').next().unwrap_or(path);
+    let path = path.strip_prefix("/en").unwrap_or(path);
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        [""] => "/",
+        ["ranking"] => "/ranking",
+        ["ranking", "calendar"] => "/ranking/calendar",
+        ["tags", _] => "/tags/{tag}",
+        ["tags", _, "artworks"] => "/tags/{tag}/artworks",
+        ["search"] => "/search",
+        ["scroll"] => "/scroll",
+        ["users", _] => "/users/{id}",
+        ["users", _, "artworks"] => "/users/{id}/artworks",
+        ["users", _, "bookmarks", "artworks"] => "/users/{id}/bookmarks/artworks",
+        ["artworks", _] => "/artworks/{id}",
+        ["comments", _] => "/comments/{id}",
+        ["replies", _] => "/replies/{id}",
+        ["jump.php"] => "/jump.php",
+        ["member_illust.php"] => "/member_illust.php",
+        ["fanbox", "creator", _] => "/fanbox/creator/{id}",
+        ["sketch"] => "/sketch",
+        ["sketch", "tags", _] => "/sketch/tags/{tag}",
+        ["sketch", "users", _] => "/sketch/users/{id}",
+        ["sketch", "items", _] => "/sketch/items/{id}",
+        ["sketch", "lives"] => "/sketch/lives",
+        ["sketch", "impressions", _] => "/sketch/impressions/{id}",
+        ["ugoira", _] => "/ugoira/{id}",
+        ["rss"] => "/rss",
+        ["stamp", _] => "/stamp/{id}",
+        ["stylesheet.css"] => "/stylesheet.css",
+        ["favicon.ico"] => "/favicon.ico",
+        ["settings"] => "/settings",
+        ["settings", "blocked", "add"] => "/settings/blocked/add",
+        ["settings", "blocked", "del"] => "/settings/blocked/del",
+        ["settings", "r18"] => "/settings/r18",
+        ["about"] => "/about",
+        ["instance.json"] => "/instance.json",
+        ["robots.txt"] => "/robots.txt",
+        ["health"] => "/health",
+        ["metrics"] => "/metrics",
+        ["imageproxy", ..] => "/imageproxy/",
+        ["simg", ..] => "/simg/",
+        ["spix", ..] => "/spix/",
+        ["spxi", ..] => "/spxi/",
+        _ => "other",
+    }
+}
+
+/* Endpoint label for upstream requests, IDs and search terms never become part of it */
+fn endpoint(uri: &http::Uri) -> &'static str {
+    let host = uri.host().unwrap_or_default();
+    let segments: Vec<&str> = uri.path().trim_start_matches('/').split('/').collect();
+
+    match (host, segments.as_slice()) {
+        /* Image paths carry dates and IDs all the way through */
+        ("i.pximg.net", _) => "i.pximg.net",
+        ("s.pximg.net", _) => "s.pximg.net",
+        ("img-sketch.pixiv.net", _) => "img-sketch.pixiv.net",
+        ("img-sketch.pximg.net", _) => "img-sketch.pximg.net",
+        ("www.pixiv.net", ["en", ""]) => "www.pixiv.net/en/",
+        ("www.pixiv.net", ["ranking.php"]) => "www.pixiv.net/ranking.php",
+        ("www.pixiv.net", ["ajax", "illust", _]) => "www.pixiv.net/ajax/illust/{id}",
+        ("www.pixiv.net", ["ajax", "illust", _, "ugoira_meta"]) => {
+            "www.pixiv.net/ajax/illust/{id}/ugoira_meta"
+        }
+        ("www.pixiv.net", ["ajax", "illusts", "comments", "roots"]) => {
+            "www.pixiv.net/ajax/illusts/comments/roots"
+        }
+        ("www.pixiv.net", ["ajax", "illusts", "comments", "replies"]) => {
+            "www.pixiv.net/ajax/illusts/comments/replies"
+        }
+        ("www.pixiv.net", ["ajax", "search", "artworks", _]) => {
+            "www.pixiv.net/ajax/search/artworks/{tag}"
+        }
+        ("www.pixiv.net", ["ajax", "user", _]) => "www.pixiv.net/ajax/user/{id}",
+        ("www.pixiv.net", ["ajax", "user", _, "profile", "all"]) => {
+            "www.pixiv.net/ajax/user/{id}/profile/all"
+        }
+        ("www.pixiv.net", ["ajax", "user", _, "profile", "illusts"]) => {
+            "www.pixiv.net/ajax/user/{id}/profile/illusts"
+        }
+        ("www.pixiv.net", ["ajax", "user", _, "illusts", "bookmarks"]) => {
+            "www.pixiv.net/ajax/user/{id}/illusts/bookmarks"
+        }
+        ("www.pixiv.net", ["fanbox", "creator", _]) => "www.pixiv.net/fanbox/creator/{id}",
+        ("sketch.pixiv.net", ["api", "items", _]) => "sketch.pixiv.net/api/items/{id}",
+        ("sketch.pixiv.net", ["api", "feedbacks", _]) => "sketch.pixiv.net/api/feedbacks/{id}",
+        ("sketch.pixiv.net", ["api", "users", "posts", "latest.json"]) => {
+            "sketch.pixiv.net/api/users/posts/latest.json"
+        }
+        ("sketch.pixiv.net", ["api", "users", _]) => "sketch.pixiv.net/api/users/{id}",
+        ("sketch.pixiv.net", ["api", "lives.json"]) => "sketch.pixiv.net/api/lives.json",
+        ("sketch.pixiv.net", ["api", "walls", "public.json"]) => {
+            "sketch.pixiv.net/api/walls/public.json"
+        }
+        ("sketch.pixiv.net", ["api", "walls", "tags", _]) => {
+            "sketch.pixiv.net/api/walls/tags/{tag}.json"
+        }
+        _ => "other",
+    }
+}
+
+/* Records every request that actually goes out to pixiv */
+pub struct UpstreamMetrics;
+
+impl Middleware for UpstreamMetrics {
+    fn handle(
+        &self,
+        request: http::Request<SendBody>,
+        next: MiddlewareNext,
+    ) -> Result<http::Response<Body>, ureq::Error> {
+        let endpoint = endpoint(request.uri());
+        let start = Instant::now();
+        let result = next.handle(request);
+
+        let status = match &result {
+            Ok(response) => response.status().as_u16(),
+            Err(ureq::Error::StatusCode(code)) => *code,
+            Err(_) => 0,
+        };
+        record_upstream(endpoint, status, start.elapsed());
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_stay_bounded() {
+        assert_eq!(route("/en/artworks/97276742"), "/artworks/{id}");
+        assert_eq!(
+            route("/tags/%E5%8E%9F%E7%A5%9E/artworks?p=2"),
+            "/tags/{tag}/artworks"
+        );
+        assert_eq!(
+            route("/imageproxy/img-master/img/2022/1_p0.jpg"),
+            "/imageproxy/"
+        );
+        assert_eq!(route("/wp-login.php"), "other");
+
+        let uri = |url: &str| url.parse::<http::Uri>().unwrap();
+        assert_eq!(
+            endpoint(&uri(
+                "https://www.pixiv.net/ajax/illust/97276742/ugoira_meta?lang=en"
+            )),
+            "www.pixiv.net/ajax/illust/{id}/ugoira_meta"
+        );
+        assert_eq!(
+            endpoint(&uri("https://i.pximg.net/img-master/img/2022/1_p0.jpg")),
+            "i.pximg.net"
+        );
+        assert_eq!(
+            endpoint(&uri("https://www.pixiv.net/ajax/search/artworks/a?word=a&p=1")),
+            endpoint(&uri("https://www.pixiv.net/ajax/search/artworks/%E5%8E%9F?word=b")),
+        );
+        assert_eq!(
+            endpoint(&uri("https://sketch.pixiv.net/api/walls/tags/a.json")),
+            endpoint(&uri("https://sketch.pixiv.net/api/walls/tags/b.json")),
+        );
+        assert_eq!(endpoint(&uri("https://www.pixiv.net/ajax/anything/new")), "other");
+        assert_eq!(endpoint(&uri("https://evil.example/")), "other");
+    }
+
+    #[test]
+    fn histogram_is_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test", "route=\"/\"");
+        assert!(out.contains("test_bucket{route=\"/\",le=\"0.01\"} 0\n"));
+        assert!(out.contains("test_bucket{route=\"/\",le=\"0.025\"} 1\n"));
+        assert!(out.contains("test_bucket{route=\"/\",le=\"30\"} 1\n"));
+        assert!(out.contains("test_bucket{route=\"/\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("test_count{route=\"/\"} 2\n"));
+    }
+}
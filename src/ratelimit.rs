@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::render::error::render_error;
+
+/* Buckets are pruned once this many checks went by */
+const PRUNE_INTERVAL: u64 = 1024;
+
+/* Routes that draw from the same budget */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteClass {
+    Page,
+    Expensive,
+    Image,
+}
+
+impl RouteClass {
+    /* None for static assets that are never limited */
+    pub fn of(path: &str) -> Option<Self> {
+        let path = path.strip_prefix("/en").unwrap_or(path);
+        let images = ["/imageproxy/", "/simg/", "/spix/", "/spxi/", "/stamp/"];
+        if images.iter().any(|prefix| path.starts_with(prefix)) {
+            Some(Self::Image)
+        } else if path.starts_with("/ugoira/") {
+            Some(Self::Expensive)
+        } else if matches!(
+            path,
+            "/stylesheet.css" | "/favicon.ico" | "/robots.txt" | "/health"
+        ) {
+            None
+        } else {
+            Some(Self::Page)
+        }
+    }
+}
+
+/* Token bucket parameters, a zero rate means unlimited */
+#[derive(Clone, Copy)]
+pub struct Budget {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+pub struct Budgets {
+    pub pages: Budget,
+    pub expensive: Budget,
+    pub images: Budget,
+}
+
+impl Budgets {
+    fn get(&self, class: RouteClass) -> Budget {
+        match class {
+            RouteClass::Page => self.pages,
+            RouteClass::Expensive => self.expensive,
+            RouteClass::Image => self.images,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_second).min(budget.burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<(IpAddr, RouteClass), Bucket>,
+    checks: u64,
+}
+
+/* Per client token buckets, separate for every route class */
+pub struct RateLimiter {
+    budgets: Budgets,
+    /* Take the client address from X-Forwarded-For, only safe behind a reverse proxy */
+    trust_forwarded_for: bool,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(budgets: Budgets, trust_forwarded_for: bool) -> Self {
+        Self {
+            budgets,
+            trust_forwarded_for,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /* Returns a 429 page if the client used up its budget for this route */
+    pub fn check(&self, request: &rouille::Request) -> Option<rouille::Response> {
+        let class = RouteClass::of(&request.url())?;
+        let client = client_ip(request, self.trust_forwarded_for);
+        let wait = self.take(client, class, Instant::now()).err()?;
+
+        let seconds = wait.as_secs().max(1);
+        let message = format!("Too many requests, try again in {seconds} seconds");
+        Some(
+            rouille::Response::html(render_error(429, &message))
+                .with_status_code(429)
+                .with_unique_header("Retry-After", seconds.to_string()),
+        )
+    }
+
+    /* Takes a token, or returns how long until one becomes available */
+    fn take(&self, client: IpAddr, class: RouteClass, now: Instant) -> Result<(), Duration> {
+        let budget = self.budgets.get(class);
+        if budget.per_second <= 0.0 {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.checks += 1;
+        if buckets.checks.is_multiple_of(PRUNE_INTERVAL) {
+            /* Full buckets carry no information */
+            let budgets = &self.budgets;
+            buckets.map.retain(|(_, class), bucket| {
+                let budget = budgets.get(*class);
+                bucket.refill(budget, now);
+                bucket.tokens < budget.burst
+            });
+        }
+
+        let bucket = buckets.map.entry((client, class)).or_insert(Bucket {
+            tokens: budget.burst,
+            updated: now,
+        });
+        bucket.refill(budget, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / budget.per_second,
+            ))
+        }
+    }
+}
+
+/* The address appended by the reverse proxy is the last one in the header */
+fn client_ip(request: &rouille::Request, trust_forwarded_for: bool) -> IpAddr {
+    let forwarded = request
+        .header("X-Forwarded-For")
+        .filter(|_| trust_forwarded_for)
+        .and_then(|header| header.rsplit(',').next())
+        .and_then(|address| address.trim().parse().ok());
+    forwarded.unwrap_or_else(|| request.remote_addr().ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn any_path_has_a_class(path in any::<String>()) {
+            let _ = RouteClass::of(&path);
+        }
+    }
+
+    fn limiter() -> RateLimiter {
+        let budget = |per_second, burst| Budget { per_second, burst };
+        RateLimiter::new(
+            Budgets {
+                pages: budget(1.0, 2.0),
+                expensive: budget(0.1, 1.0),
+                images: budget(0.0, 0.0),
+            },
+            true,
+        )
+    }
+
+    #[test]
+    fn classifies_routes() {
+        assert_eq!(RouteClass::of("/search"), Some(RouteClass::Page));
+        assert_eq!(RouteClass::of("/en/ugoira/1"), Some(RouteClass::Expensive));
+        assert_eq!(
+            RouteClass::of("/imageproxy/img-master/1.jpg"),
+            Some(RouteClass::Image)
+        );
+        assert_eq!(RouteClass::of("/stylesheet.css"), None);
+    }
+
+    #[test]
+    fn refills_buckets() {
+        let limiter = limiter();
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let now = Instant::now();
+
+        assert!(limiter.take(client, RouteClass::Page, now).is_ok());
+        assert!(limiter.take(client, RouteClass::Page, now).is_ok());
+        let wait = limiter.take(client, RouteClass::Page, now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+
+        /* Other budgets and other clients are unaffected */
+        assert!(limiter.take(client, RouteClass::Expensive, now).is_ok());
+        assert!(limiter.take(client, RouteClass::Image, now).is_ok());
+        assert!(limiter
+            .take([192, 0, 2, 2].into(), RouteClass::Page, now)
+            .is_ok());
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.take(client, RouteClass::Page, later).is_ok());
+        assert!(limiter.take(client, RouteClass::Page, later).is_err());
+    }
+
+    #[test]
+    fn reads_forwarded_for() {
+        let request = |trust| {
+            let request = rouille::Request::fake_http_from(
+                "127.0.0.1:8000".parse().unwrap(),
+                "GET",
+                "/",
+                vec![("X-Forwarded-For".into(), "203.0.113.9, 198.51.100.7".into())],
+                vec![],
+            );
+            client_ip(&request, trust)
+        };
+
+        assert_eq!(request(true), IpAddr::from([198, 51, 100, 7]));
+        assert_eq!(request(false), IpAddr::from([127, 0, 0, 1]));
+    }
+}
@@ -1,10 +1,12 @@
 use maud::{html, Markup};
 
-use crate::{api::search::SearchRequest, render::svg};
+use crate::{
+    api::{ranking::RankingRequest, search::SearchRequest},
+    render::svg,
+};
 
-fn render_alt(options: &str, page: u32) -> Markup {
+fn render_rss(options: &str) -> Markup {
     html! {
-        a href=(format!("/scroll{options}&p={page}", page = page)) { "Scroll..." }
         a href=(format!("/rss{}", options)) {
             (svg::rss())
             "RSS"
@@ -12,6 +14,13 @@ fn render_alt(options: &str, page: u32) -> Markup {
     }
 }
 
+fn render_alt(options: &str, page: u32) -> Markup {
+    html! {
+        a href=(format!("/scroll{options}&p={page}", page = page)) { "Scroll..." }
+        (render_rss(options))
+    }
+}
+
 pub fn render_alt_author(id: u64, page: u32) -> Markup {
     let options = format!("?qtype=author&q={id}");
     render_alt(&options, page)
@@ -26,3 +35,9 @@ pub fn render_alt_search(q: &str, query: &SearchRequest) -> Markup {
     );
     render_alt(&options, query.page)
 }
+
+/* Rankings have no scroll view, the feed always starts at the top */
+pub fn render_alt_ranking(query: &RankingRequest) -> Markup {
+    let options = format!("?qtype=ranking&{}", query.query());
+    render_rss(&options)
+}
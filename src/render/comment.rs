@@ -23,7 +23,7 @@ impl Render for &PixivComment {
                     p.date { (&self.comment_date) }
                     @if self.has_replies.unwrap_or(false) {
                         div.replies {
-                            button endpoint=(format!("/replies/{}", self.id)) onclick="inject(this)" {
+                            button endpoint=(format!("/replies/{}", self.id)) {
                                 "Load replies"
                             }
                         }
@@ -13,6 +13,13 @@ impl Display for ApiError {
                 f.write_fmt(format_args!("{}: {}", *code, message))
             }
             ApiError::Internal(message) => f.write_fmt(format_args!("{}", message)),
+            ApiError::RateLimited(Some(seconds)) => f.write_fmt(format_args!(
+                "pixiv is rate-limiting this instance, try again in {} seconds",
+                seconds
+            )),
+            ApiError::RateLimited(None) => {
+                f.write_str("pixiv is rate-limiting this instance, try again later")
+            }
         }
     }
 }
@@ -22,6 +29,7 @@ impl maud::Render for ApiError {
         match self {
             ApiError::External(code, message) => render_error(*code, message),
             ApiError::Internal(message) => render_error(500, message),
+            ApiError::RateLimited(_) => render_error(429, &self.to_string()),
         }
     }
 }
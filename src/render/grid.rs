@@ -1,10 +1,116 @@
 use std::collections::HashSet;
 
-use crate::{api::common::PixivSearchResult, render::svg, util};
+use crate::{
+    api::{common::PixivSearchResult, ranking::RankingItem},
+    config::{ContentPolicy, Work},
+    render::svg,
+    util,
+};
 
 use maud::html;
 
-pub fn render_grid(list: &[PixivSearchResult], blocked_users: &HashSet<u64>, load_more: Option<maud::Markup>) -> maud::Markup {
+/* A work as the grid shows it, search results and ranking items alike */
+pub trait GridItem: Work {
+    fn id(&self) -> u64;
+    fn title(&self) -> &str;
+    fn thumbnail(&self) -> &str;
+    fn size(&self) -> (u32, u32);
+    fn page_count(&self) -> u64;
+    fn is_ugoira(&self) -> bool;
+    /* Masked works have no page to link to */
+    fn is_masked(&self) -> bool {
+        false
+    }
+    /* Current and previous rank, the latter 0 for works new to the ranking */
+    fn rank(&self) -> Option<(u32, u32)> {
+        None
+    }
+    /* Shown below the title where works of many users are mixed */
+    fn user_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl GridItem for PixivSearchResult {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn thumbnail(&self) -> &str {
+        &self.url
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn page_count(&self) -> u64 {
+        self.page_count.into()
+    }
+
+    fn is_ugoira(&self) -> bool {
+        self.illust_type == 2
+    }
+
+    fn is_masked(&self) -> bool {
+        self.is_masked
+    }
+}
+
+impl GridItem for RankingItem {
+    fn id(&self) -> u64 {
+        self.illust_id.into()
+    }
+
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn thumbnail(&self) -> &str {
+        &self.url
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn page_count(&self) -> u64 {
+        self.illust_page_count
+    }
+
+    fn is_ugoira(&self) -> bool {
+        self.illust_type == 2
+    }
+
+    fn rank(&self) -> Option<(u32, u32)> {
+        Some((self.rank, self.yes_rank))
+    }
+
+    fn user_name(&self) -> Option<&str> {
+        Some(&self.user_name).filter(|name| !name.is_empty()).map(String::as_str)
+    }
+}
+
+fn render_rank(rank: u32, previous: u32) -> maud::Markup {
+    html! {
+        div.rank.hover {
+            "#" (rank)
+            @if previous == 0 {
+                " " span.new { "new" }
+            } @else if previous > rank {
+                " " span.up title=(format!("Up from #{previous}")) { "▲" }
+            } @else if previous < rank {
+                " " span.down title=(format!("Down from #{previous}")) { "▼" }
+            }
+        }
+    }
+}
+
+pub fn render_grid<T: GridItem>(list: &[T], blocked_users: &HashSet<u64>, policy: &ContentPolicy, load_more: Option<maud::Markup>) -> maud::Markup {
     html! {
         svg style="display:none" {
             defs {
@@ -13,7 +119,7 @@ pub fn render_grid(list: &[PixivSearchResult], blocked_users: &HashSet<u64>, loa
             }
         }
         ul.search {
-            (render_grid_contents(list, blocked_users))
+            (render_grid_contents(list, blocked_users, policy))
         }
         @if let Some(load_more) = load_more {
             (load_more)
@@ -21,33 +127,85 @@ pub fn render_grid(list: &[PixivSearchResult], blocked_users: &HashSet<u64>, loa
     }
 }
 
-pub fn render_grid_contents(list: &[PixivSearchResult], blocked_users: &HashSet<u64>) -> maud::Markup {
+pub fn render_grid_contents<T: GridItem>(list: &[T], blocked_users: &HashSet<u64>, policy: &ContentPolicy) -> maud::Markup {
     html! {
-        @for artwork in list.iter().filter(|a| !blocked_users.contains(&a.user_id)) {
-            @let link = format!("/artworks/{}", artwork.id);
-            @let link = if !artwork.is_masked { Some(&link) } else { None };
-            @let img = util::image_square_to_master(&artwork.url);
-            @let (width, height) = util::scale_by_aspect_ratio(artwork.width, artwork.height, 200, 400);
+        @for artwork in list.iter().filter(|a| !blocked_users.contains(&a.user_id()) && !policy.hides(*a)) {
+            @let link = format!("/artworks/{}", artwork.id());
+            @let link = if !artwork.is_masked() { Some(&link) } else { None };
+            @let img = util::image_square_to_master(artwork.thumbnail());
+            @let (width, height) = artwork.size();
+            @let (width, height) = util::scale_by_aspect_ratio(width, height, 200, 400);
             li {
                 a href=[link] {
                     div {
-                        @if artwork.r18 == 1 {
-                            div.warn.hover { "R-18" }
+                        @match artwork.x_restrict() {
+                            0 => {}
+                            1 => div.warn.hover { "R-18" },
+                            _ => div.warn.hover { "R-18G" },
                         }
-                        @if artwork.page_count > 1 {
+                        @if artwork.page_count() > 1 {
                             div.count.hover {
                                 svg { use href="#page" {} }
-                                (artwork.page_count)
+                                (artwork.page_count())
                             }
                         }
-                        @if artwork.illust_type == 2 {
+                        @if artwork.is_ugoira() {
                             svg.play { use href="#play" {} }
                         }
-                        img src=(&img) width=(width) height=(height) alt=(&artwork.title);
+                        @if let Some((rank, previous)) = artwork.rank() {
+                            (render_rank(rank, previous))
+                        }
+                        img src=(&img) width=(width) height=(height) alt=(artwork.title());
                     }
-                    span { (&artwork.title) }
+                    span { (artwork.title()) }
+                }
+                @if let Some(name) = artwork.user_name() {
+                    a.user href=(format!("/users/{}", artwork.user_id())) { (name) }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ranking::Ranking;
+
+    #[test]
+    fn ranking_items_show_rank_and_movement() {
+        let item = |id: u64, rank: u32, yes_rank: u32| {
+            serde_json::json!({
+                "title": format!("work {id}"),
+                "url": "https://i.pximg.net/c/240x480/img-master/img/2024/01/01/00/00/00/1_p0_master1200.jpg",
+                "illust_id": id,
+                "width": 1000,
+                "height": 1000,
+                "illust_page_count": "3",
+                "illust_upload_timestamp": 1704067200,
+                "rank": rank,
+                "yes_rank": yes_rank,
+                "user_id": id * 10,
+                "user_name": "artist",
+                "illust_type": "2",
+            })
+        };
+        let ranking = serde_json::json!({
+            "contents": [item(1, 1, 4), item(2, 2, 0), item(3, 3, 1)],
+            "date": "20240101",
+            "prev_date": "20231231",
+            "next_date": false,
+            "rank_total": 3,
+        });
+        let ranking = serde_json::from_value::<Ranking>(ranking).unwrap();
+        assert_eq!(ranking.contents[0].illust_page_count, 3);
+
+        let html = render_grid_contents(&ranking.contents, &HashSet::from([30]), &ContentPolicy::default()).into_string();
+        assert!(html.contains(r#"#1 <span class="up" title="Up from #4">"#));
+        assert!(html.contains(r#"#2 <span class="new">new</span>"#));
+        assert!(html.contains(r#"<a class="user" href="/users/10">artist</a>"#));
+        assert!(html.contains("#page"));
+        assert!(html.contains("#play"));
+        assert!(!html.contains("work 3"));
+    }
+}
@@ -5,6 +5,7 @@ pub mod document;
 pub mod error;
 pub mod grid;
 pub mod nav;
+pub mod sanitize;
 pub mod search;
 pub mod sketch;
 pub mod svg;
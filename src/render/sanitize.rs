@@ -0,0 +1,143 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+
+use maud::PreEscaped;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::util;
+
+/* Formatting pixiv allows in descriptions and bios, everything else is dropped */
+const TAGS: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "em",
+    "i",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "s",
+    "span",
+    "strong",
+    "u",
+    "ul",
+];
+
+/* pixiv paths this instance serves itself */
+const LOCAL_PATHS: &[&str] = &[
+    "/artworks/",
+    "/users/",
+    "/tags/",
+    "/jump.php",
+    "/member_illust.php",
+    "/fanbox/",
+];
+
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut builder = ammonia::Builder::empty();
+    builder
+        .tags(TAGS.iter().copied().collect())
+        .tag_attributes(HashMap::from([("a", HashSet::from(["href"]))]))
+        .url_schemes(HashSet::from(["http", "https"]))
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("a", "href") => Some(rewrite_link(value)),
+            _ => Some(value.into()),
+        });
+    builder
+});
+
+/* Cleans HTML that came from pixiv so it can be embedded as is */
+pub fn sanitize(html: &str) -> PreEscaped<String> {
+    PreEscaped(SANITIZER.clean(html).to_string())
+}
+
+/* Points links at this instance, anything external goes through the jump page */
+fn rewrite_link(href: &str) -> Cow<'_, str> {
+    let href = href.trim();
+    let Some(rest) = ["https://", "http://", "//"]
+        .iter()
+        .find_map(|scheme| href.strip_prefix(scheme))
+    else {
+        /* Relative links were meant for pixiv.net, whose paths are mirrored here */
+        if util::is_local(href) {
+            return href.into();
+        }
+        return format!("/jump.php?{}", utf8_percent_encode(href, NON_ALPHANUMERIC)).into();
+    };
+
+    let split = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, path) = rest.split_at(split);
+    let path = if path.is_empty() { "/" } else { path };
+
+    match host.to_ascii_lowercase().as_str() {
+        "www.pixiv.net" | "pixiv.net" => {
+            let local = path.strip_prefix("/en").unwrap_or(path);
+            if local == "/" || LOCAL_PATHS.iter().any(|prefix| local.starts_with(prefix)) {
+                return local.to_owned().into();
+            }
+        }
+        "i.pximg.net" => return format!("/imageproxy{path}").into(),
+        "s.pximg.net" => return format!("/simg{path}").into(),
+        "img-sketch.pixiv.net" => return format!("/spix{path}").into(),
+        "img-sketch.pximg.net" => return format!("/spxi{path}").into(),
+        _ => {}
+    }
+
+    format!("/jump.php?{}", utf8_percent_encode(href, NON_ALPHANUMERIC)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_unsafe_markup() {
+        assert_eq!(
+            sanitize(r#"Hi<script>alert(1)</script><img src=x onerror=alert(1)><br />"#).0,
+            "Hi<br>"
+        );
+        assert_eq!(
+            sanitize(r#"<strong onclick="steal()">bold</strong><iframe src="/"></iframe>"#).0,
+            "<strong>bold</strong>"
+        );
+        assert_eq!(
+            sanitize(r#"<a href="javascript:alert(1)" target="_blank">x</a>"#).0,
+            r#"<a rel="noopener noreferrer nofollow">x</a>"#
+        );
+    }
+
+    #[test]
+    fn rewrites_pixiv_links() {
+        assert_eq!(
+            rewrite_link("https://www.pixiv.net/en/artworks/97276742"),
+            "/artworks/97276742"
+        );
+        assert_eq!(
+            rewrite_link("/jump.php?https%3A%2F%2Fx.com"),
+            "/jump.php?https%3A%2F%2Fx.com"
+        );
+        assert_eq!(
+            rewrite_link("https://i.pximg.net/img-original/img/1_p0.png"),
+            "/imageproxy/img-original/img/1_p0.png"
+        );
+        assert_eq!(
+            rewrite_link("https://x.com/pixiv"),
+            "/jump.php?https%3A%2F%2Fx%2Ecom%2Fpixiv"
+        );
+        assert_eq!(rewrite_link("/users/11"), "/users/11");
+        assert_eq!(rewrite_link("/\\evil.example"), "/jump.php?%2F%5Cevil%2Eexample");
+        assert_eq!(rewrite_link("/\t/evil.example"), "/jump.php?%2F%09%2Fevil%2Eexample");
+        assert_eq!(
+            rewrite_link("https://www.pixiv.net/novel/show.php?id=1"),
+            "/jump.php?https%3A%2F%2Fwww%2Epixiv%2Enet%2Fnovel%2Fshow%2Ephp%3Fid%3D1"
+        );
+    }
+}
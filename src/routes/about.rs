@@ -1,16 +1,92 @@
-use crate::render::document::document;
+use crate::{config::Config, render::document::document};
 use maud::html;
+use serde::Serialize;
 
-pub fn about() -> rouille::Response {
+/* Everything /about and /instance.json tell about this instance */
+#[derive(Serialize)]
+pub struct Instance {
+    name: String,
+    version: &'static str,
+    description: String,
+    contact: Option<String>,
+    source: String,
+    /* Route groups and optional parts that are available */
+    features: Vec<&'static str>,
+    content: ContentSummary,
+}
+
+/* The block lists themselves are not published */
+#[derive(Serialize)]
+struct ContentSummary {
+    safe_only: bool,
+    hide_ai: bool,
+    blocked_users: usize,
+    blocked_tags: usize,
+}
+
+impl Instance {
+    pub fn new(config: &Config) -> Self {
+        let routes = &config.routes;
+        let features = [
+            ("search", routes.search),
+            ("users", routes.users),
+            ("artworks", routes.artworks),
+            ("comments", routes.comments),
+            ("redirects", routes.redirects),
+            ("sketch", routes.sketch),
+            ("ugoira", routes.ugoira && cfg!(feature = "ugoira")),
+            ("rss", routes.rss),
+            ("imageproxy", routes.imageproxy),
+            ("image_cache", routes.imageproxy && config.cache.image_dir.is_some()),
+            ("settings", routes.settings),
+            ("metrics", routes.metrics),
+        ];
+
+        Self {
+            name: config.instance.name.clone(),
+            version: env!("CARGO_PKG_VERSION"),
+            description: config.instance.description.clone(),
+            contact: config.instance.contact.clone(),
+            source: config.instance.source.clone(),
+            features: features
+                .into_iter()
+                .filter_map(|(name, enabled)| enabled.then_some(name))
+                .collect(),
+            content: ContentSummary {
+                safe_only: config.content.safe_only,
+                hide_ai: config.content.hide_ai,
+                blocked_users: config.content.blocked_users.len(),
+                blocked_tags: config.content.blocked_tags.len(),
+            },
+        }
+    }
+}
+
+pub fn about(instance: &Instance) -> rouille::Response {
+    let content = &instance.content;
     let document = document(
         "About",
         html! {
-            h1 { "About" }
-            p { "This is a simple Pixiv API client written in Rust." }
-            p { "The source code is available on " a href="https://github.com/HookedBehemoth/pixiv-proxy" { "GitHub" } "." }
+            h1 { "About " (instance.name) }
+            p { (instance.description) }
+            p { "The source code is available at " a href=(instance.source) { (instance.source) } "." }
+            p { "Version " (instance.version) ", features: " (instance.features.join(", ")) "." }
+
+            @if content.safe_only || content.hide_ai || content.blocked_users > 0 || content.blocked_tags > 0 {
+                h2 { "Content policy" }
+                ul {
+                    @if content.safe_only { li { "R-18 and R-18G works are hidden." } }
+                    @if content.hide_ai { li { "AI generated works are hidden." } }
+                    @if content.blocked_users > 0 { li { "Works of " (content.blocked_users) " users are hidden." } }
+                    @if content.blocked_tags > 0 { li { "Works with " (content.blocked_tags) " tags are hidden." } }
+                }
+            }
 
-            h2 { "Contact" }
-            p { "If you have any questions, feel free to contact me at " a href = "mailto:info@cunnycon.org" { "info@cunnycon.org" } "." }
+            @if let Some(contact) = &instance.contact {
+                h2 { "Contact" }
+                @let link = if contact.contains("://") { contact.clone() } else { format!("mailto:{contact}") };
+                p { "If you have any questions, feel free to contact the operator at " a href=(link) { (contact) } "." }
+            }
 
             h2 { "License" }
             p { "This project is licensed under the " a href="https://www.gnu.org/licenses/licenses.html#AGPL" { "GNU Affero General Public License" } "." }
@@ -20,3 +96,14 @@ pub fn about() -> rouille::Response {
 
     rouille::Response::html(document.into_string())
 }
+
+pub fn instance_json(instance: &Instance) -> rouille::Response {
+    /* Instance lists and redirect extensions read this from other origins */
+    rouille::Response::json(instance)
+        .with_public_cache(3600)
+        .with_unique_header("Access-Control-Allow-Origin", "*")
+}
+
+pub fn robots(robots_txt: &str) -> rouille::Response {
+    rouille::Response::text(robots_txt).with_public_cache(86400)
+}
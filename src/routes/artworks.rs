@@ -2,12 +2,32 @@ use maud::{html, PreEscaped};
 
 use crate::{
     api::{artwork::fetch_artwork, error::ApiError},
-    render::{datetime::DateTimeWrapper, document::document, svg},
-    util,
+    config::ContentPolicy,
+    render::{datetime::DateTimeWrapper, document::document, sanitize::sanitize, svg},
+    security, util,
 };
 
-pub fn artwork(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiError> {
+/* Comments and ugoira only know the artwork id, the artwork tells whether they may be shown */
+pub fn check_policy(client: &ureq::Agent, id: u64, policy: &ContentPolicy) -> Result<(), ApiError> {
+    if policy.hides_nothing() {
+        return Ok(());
+    }
     let artwork = fetch_artwork(client, id)?;
+    if policy.hides(&artwork) {
+        return Err(ContentPolicy::blocked());
+    }
+    Ok(())
+}
+
+pub fn artwork(
+    client: &ureq::Agent,
+    id: u64,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
+    let artwork = fetch_artwork(client, id)?;
+    if policy.hides(&artwork) {
+        return Err(ContentPolicy::blocked());
+    }
 
     let image = &artwork.urls.original;
     let date = chrono::DateTime::parse_from_rfc3339(&artwork.create_date);
@@ -22,7 +42,7 @@ pub fn artwork(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiEr
             p.illust__author { a href=(&link) { (&artwork.user_name) } }
             /* Description */
             @if !artwork.description.is_empty() {
-                p { (PreEscaped(&artwork.description)) }
+                p { (sanitize(&artwork.description)) }
             }
             /* Tags */
             (artwork.tags)
@@ -64,7 +84,7 @@ pub fn artwork(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiEr
             /* Comments */
             @if artwork.comment_count > 0 {
                 div.comments_wrapper {
-                    button endpoint=(format!("/comments/{}", id)) type="button" onclick="inject(this)" {
+                    button endpoint=(format!("/comments/{}", id)) type="button" {
                         "Load Comments"
                     }
                 }
@@ -99,7 +119,7 @@ pub fn artwork(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiEr
             meta property="og:description" content=(&description);
             /* Insert javascript if needed */
             @if artwork.comment_count > 0 {
-                script {
+                script nonce=(security::nonce()) {
                     (PreEscaped(include_str!("../dynamic.js")))
                 }
             }
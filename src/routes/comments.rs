@@ -4,13 +4,15 @@ use crate::api::{
     comments::{fetch_comments, fetch_replies},
     error::ApiError,
 };
-use crate::get_param_or_num;
+use crate::{config::ContentPolicy, get_param_or_num, routes::artworks::check_policy};
 
 pub fn comments(
     client: &ureq::Agent,
     id: u64,
     query: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
+    check_policy(client, id, policy)?;
     let offset = get_param_or_num!(query, "offset", 0);
     let limit = get_param_or_num!(query, "limit", 100);
 
@@ -23,7 +25,7 @@ pub fn comments(
             }
         }
         @if roots.has_next {
-            button endpoint=(format!("/comments/{}?offset={}&limit={}", id, offset + limit, limit)) onclick="inject(this)" {
+            button endpoint=(format!("/comments/{}?offset={}&limit={}", id, offset + limit, limit)) {
                 "Load more..."
             }
         }
@@ -43,7 +45,7 @@ pub fn replies(
 
     let document = html! {
         @if replies.has_next {
-            button endpoint=(format!("/replies/{}?page={}", id, page + 1)) onclick="inject(this, false)" {
+            button endpoint=(format!("/replies/{}?page={}", id, page + 1)) data-prepend {
                 "Load older replies"
             }
         }
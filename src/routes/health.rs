@@ -0,0 +1,19 @@
+use serde::Serialize;
+
+use crate::api::session::{SessionPool, SessionStatus};
+
+#[derive(Serialize)]
+struct Health {
+    healthy: bool,
+    sessions: Vec<SessionStatus>,
+}
+
+pub fn health(sessions: &SessionPool) -> rouille::Response {
+    let sessions = sessions.status();
+    let health = Health {
+        healthy: sessions.iter().any(|session| session.healthy),
+        sessions,
+    };
+
+    rouille::Response::json(&health).with_no_cache()
+}
@@ -1,63 +1,159 @@
-use crate::api::error::ApiError;
+use crate::{
+    api::error::ApiError,
+    imagecache::{CachedImage, ImageCache, ImageMeta},
+    metrics::{self, CountingReader},
+};
 
 macro_rules! make_proxy {
-    ($name:ident, $path:expr, $dest:tt) => {
+    ($name:ident, $path:expr, $dest:tt, $shapes:expr) => {
         pub fn $name(
             client: &ureq::Agent,
             path: &str,
             request: &rouille::Request,
+            cache: Option<&ImageCache>,
         ) -> Option<Result<rouille::Response, ApiError>> {
             let path = path.strip_prefix($path)?;
+            if !is_image_path(path, $shapes) {
+                return Some(Ok(
+                    rouille::Response::text("Not a pixiv image").with_status_code(400)
+                ));
+            }
             let url = format!($dest, path);
-            Some(proxy(&client, &url, request))
+            Some(proxy(&client, &url, request, cache))
         }
     };
 }
 
-make_proxy!(imageproxy, "/imageproxy/", "https://i.pximg.net/{}");
-make_proxy!(s_imageproxy, "/simg/", "https://s.pximg.net/{}");
-make_proxy!(spix_imageproxy, "/spix/", "https://img-sketch.pixiv.net/{}");
-make_proxy!(spxi_imageproxy, "/spxi/", "https://img-sketch.pximg.net/{}");
+/* Directories pixiv serves images from, per host */
+const PXIMG_PATHS: &[&str] = &[
+    "img-original/",
+    "img-master/",
+    "c/",
+    "custom-thumb/",
+    "user-profile/",
+    "background/",
+    "workspace/",
+    "novel-cover-master/",
+    "novel-cover-original/",
+    "imgaz/",
+];
+const S_PXIMG_PATHS: &[&str] = &["common/images/", "www/images/"];
+const SKETCH_PATHS: &[&str] = &["uploads/", "c!/"];
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp"];
+
+make_proxy!(imageproxy, "/imageproxy/", "https://i.pximg.net/{}", PXIMG_PATHS);
+make_proxy!(s_imageproxy, "/simg/", "https://s.pximg.net/{}", S_PXIMG_PATHS);
+make_proxy!(spix_imageproxy, "/spix/", "https://img-sketch.pixiv.net/{}", SKETCH_PATHS);
+make_proxy!(spxi_imageproxy, "/spxi/", "https://img-sketch.pximg.net/{}", SKETCH_PATHS);
+
+/* Known directory, plain path segments and an image extension */
+fn is_image_path(path: &str, shapes: &[&str]) -> bool {
+    let known = shapes.iter().any(|prefix| path.starts_with(prefix));
+    /* Sketch thumbnails carry options like "c!/w=240,f=webp:jpeg/" */
+    let plain = path
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"/_-.,=!:".contains(&b));
+    let segments = path
+        .split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    let image = path.rsplit_once('.').is_some_and(|(_, extension)| {
+        IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    });
+
+    known && plain && segments && image
+}
 
 pub fn stamp(
     client: &ureq::Agent,
     id: u32,
     request: &rouille::Request,
+    cache: Option<&ImageCache>,
 ) -> Result<rouille::Response, ApiError> {
     let url = format!(
         "https://s.pximg.net/common/images/stamp/generated-stamps/{}_s.jpg?20180605",
         id
     );
 
-    proxy(client, &url, request)
+    proxy(client, &url, request, cache)
 }
 
-/* Note: passing these to the client should be avoided */
-const FORBIDDEN_CLIENT_HEADERS: &[&str] = &["connection", "cookies", "set-cookie"];
-const FORBIDDEN_SERVER_HEADERS: &[&str] =
-    &["connection", "cookie", "user-agent", "host", "referer"];
+/* Only these request headers are passed on to pixiv */
+const FORWARDED_REQUEST_HEADERS: &[&str] =
+    &["range", "if-none-match", "if-modified-since", "accept"];
+/* Only these response headers are passed on to the client */
+const FORWARDED_RESPONSE_HEADERS: &[&str] = &[
+    "content-type",
+    "content-range",
+    "accept-ranges",
+    "cache-control",
+    "expires",
+    "last-modified",
+    "etag",
+    "age",
+];
+/* Note: the cache has to see full responses, the client's validators are checked locally */
+const CONDITIONAL_HEADERS: &[&str] = &["if-none-match", "if-modified-since", "range", "if-range"];
 
 fn proxy(
     client: &ureq::Agent,
     url: &str,
     request: &rouille::Request,
+    cache: Option<&ImageCache>,
 ) -> Result<rouille::Response, ApiError> {
-    let mut req = client.get(url);
+    /* Partial requests are passed through untouched */
+    let cache = cache.filter(|_| request.header("Range").is_none());
 
-    for header in request
-        .headers()
-        .filter(|(h, _)| !FORBIDDEN_SERVER_HEADERS.contains(&h.to_lowercase().as_str()))
-    {
-        req = req.header(header.0, header.1);
+    let mut changed = None;
+    if let Some(cache) = cache {
+        let cached = cache.lookup(url);
+        metrics::record_cache("image", cached.as_ref().is_some_and(|c| c.meta.is_fresh()));
+        if let Some(mut cached) = cached {
+            if cached.meta.is_fresh() {
+                return Ok(cached_response(cached, request));
+            }
+
+            let mut req = client.get(url);
+            if let Some(etag) = &cached.meta.etag {
+                req = req.header("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &cached.meta.last_modified {
+                req = req.header("If-Modified-Since", last_modified);
+            }
+            let res = req.call()?;
+            if res.status() == 304 {
+                cached.meta.revalidated(res.headers());
+                cache.update(&cached.meta);
+                return Ok(cached_response(cached, request));
+            }
+            changed = Some(res);
+        }
     }
 
-    let res = req.call()?;
+    let res = match changed {
+        Some(res) => res,
+        None => {
+            let mut req = client.get(url);
+
+            for header in request.headers().filter(|(h, _)| {
+                let h = h.to_lowercase();
+                let conditional = cache.is_some() && CONDITIONAL_HEADERS.contains(&h.as_str());
+                FORWARDED_REQUEST_HEADERS.contains(&h.as_str()) && !conditional
+            }) {
+                req = req.header(header.0, header.1);
+            }
+
+            req.call()?
+        }
+    };
     let status = res.status();
+    let meta = cache
+        .filter(|_| status == 200)
+        .and_then(|_| ImageMeta::from_headers(url, res.headers()));
 
     let headers = res.headers()
         .iter()
-        .filter(|&(name, _)| !FORBIDDEN_CLIENT_HEADERS.contains(&name.as_str()))
-        .map(|(name, value)| (name.to_string().into(), value.to_str().unwrap().to_string().into()))
+        .filter(|&(name, _)| FORWARDED_RESPONSE_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string().into(), value.to_str().ok()?.to_string().into())))
         .collect();
 
     let length = res.headers()
@@ -70,9 +166,15 @@ fn proxy(
     
     let body = res.into_body();
 
+    let reader = CountingReader(body.into_reader());
+    let reader: Box<dyn std::io::Read + Send> = match (cache, meta) {
+        (Some(cache), Some(meta)) => Box::new(cache.store(meta, reader)),
+        _ => Box::new(reader),
+    };
+
     let reader = match length {
-        Some(Ok(len)) => rouille::ResponseBody::from_reader_and_size(body.into_reader(), len),
-        _ => rouille::ResponseBody::from_reader(body.into_reader()),
+        Some(Ok(len)) => rouille::ResponseBody::from_reader_and_size(reader, len),
+        _ => rouille::ResponseBody::from_reader(reader),
     };
 
     Ok(rouille::Response {
@@ -82,3 +184,74 @@ fn proxy(
         upgrade: None,
     })
 }
+
+fn cached_response(cached: CachedImage, request: &rouille::Request) -> rouille::Response {
+    let meta = &cached.meta;
+
+    let not_modified = match (request.header("If-None-Match"), &meta.etag) {
+        (Some(tag), Some(etag)) => tag.split(',').any(|tag| tag.trim() == etag),
+        (None, _) => request
+            .header("If-Modified-Since")
+            .is_some_and(|since| meta.last_modified.as_deref() == Some(since)),
+        _ => false,
+    };
+
+    let mut response = if not_modified {
+        rouille::Response {
+            status_code: 304,
+            headers: vec![],
+            data: rouille::ResponseBody::empty(),
+            upgrade: None,
+        }
+    } else {
+        let content_type = meta.content_type.as_deref().unwrap_or("application/octet-stream");
+        if let Ok(file) = cached.file.metadata() {
+            metrics::record_cached_image_bytes(file.len());
+        }
+        rouille::Response::from_file(content_type.to_owned(), cached.file)
+    };
+
+    for (name, value) in [
+        ("Last-Modified", &meta.last_modified),
+        ("ETag", &meta.etag),
+        ("Cache-Control", &meta.cache_control),
+    ] {
+        if let Some(value) = value {
+            response = response.with_unique_header(name, value.clone());
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_known_image_paths() {
+        assert!(is_image_path(
+            "img-master/img/2022/03/04/00/00/05/96654321_p0_master1200.jpg",
+            PXIMG_PATHS
+        ));
+        assert!(is_image_path(
+            "c/250x250_80_a2/custom-thumb/img/2022/03/04/00/00/05/96654321_p0_custom1200.jpg",
+            PXIMG_PATHS
+        ));
+        assert!(is_image_path("common/images/emoji/101.png", S_PXIMG_PATHS));
+        assert!(is_image_path(
+            "c!/w=240,f=webp:jpeg/uploads/medium/file/4463372/8906738234023852289.jpg",
+            SKETCH_PATHS
+        ));
+    }
+
+    #[test]
+    fn rejects_other_paths() {
+        assert!(!is_image_path("img-master/img/../../ajax/user.jpg", PXIMG_PATHS));
+        assert!(!is_image_path("img-zip-ugoira/img/1_ugoira600x600.zip", PXIMG_PATHS));
+        assert!(!is_image_path("img-original/img/1_p0.svg", PXIMG_PATHS));
+        assert!(!is_image_path("img-original/img/1_p0.jpg?x=1", PXIMG_PATHS));
+        assert!(!is_image_path("ajax/illust/1.jpg", PXIMG_PATHS));
+        assert!(!is_image_path("img-master//img/1.jpg", PXIMG_PATHS));
+    }
+}
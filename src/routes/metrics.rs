@@ -0,0 +1,36 @@
+use crate::{
+    api::session::SessionPool,
+    metrics::{export, Gauge},
+    server,
+};
+
+pub fn metrics(sessions: &SessionPool) -> rouille::Response {
+    let sessions = sessions.status();
+    let healthy = sessions.iter().filter(|session| session.healthy).count();
+    let load = server::load();
+
+    let body = export(&[
+        Gauge {
+            name: "reapixa_sessions",
+            help: "Configured pixiv sessions.",
+            value: sessions.len() as f64,
+        },
+        Gauge {
+            name: "reapixa_sessions_healthy",
+            help: "pixiv sessions currently in rotation.",
+            value: healthy as f64,
+        },
+        Gauge {
+            name: "reapixa_workers_busy",
+            help: "Workers currently handling a request.",
+            value: load.busy as f64,
+        },
+        Gauge {
+            name: "reapixa_requests_queued",
+            help: "Requests waiting for a free worker.",
+            value: load.queued as f64,
+        },
+    ]);
+
+    rouille::Response::from_data("text/plain; version=0.0.4", body).with_no_cache()
+}
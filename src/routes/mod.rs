@@ -3,7 +3,9 @@ pub mod artworks;
 pub mod comments;
 pub mod css;
 pub mod favicon;
+pub mod health;
 pub mod imageproxy;
+pub mod metrics;
 pub mod ranking;
 pub mod redirect;
 pub mod rss;
@@ -1,49 +1,272 @@
+use chrono::{Datelike, Months, NaiveDate, Utc};
 use maud::html;
 
 use crate::{
     api::{
+        cache::ResponseCache,
         error::ApiError,
-        ranking::fetch_ranking,
+        ranking::{cached_ranking, fetch_ranking, parse_date, RankingMode, RankingRequest},
         search::{SearchMode, SearchOrder, SearchRating},
     },
-    get_param_or_num,
-    render::{document::document, nav::render_nav, search::render_options},
+    config::ContentPolicy,
+    render::{
+        alt::render_alt_ranking, document::document, grid::render_grid, nav::render_nav,
+        search::render_options,
+    },
+    routes::settings::{get_blocked_userids, shows_r18},
     util,
 };
 
+/* R-18 modes need the operator to allow them and the visitor to opt in */
+pub fn check_mode(mode: RankingMode, policy: &ContentPolicy, show_r18: bool) -> Result<(), ApiError> {
+    if mode.x_restrict() == 0 {
+        Ok(())
+    } else if policy.safe_only {
+        Err(ContentPolicy::blocked())
+    } else if !show_r18 {
+        Err(ApiError::External(
+            403,
+            "R-18 rankings have to be enabled in the settings first".into(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn render_modes(current: &RankingRequest, r18: bool) -> maud::Markup {
+    html! {
+        div.category.modes {
+            @for mode in RankingMode::ALL.into_iter().filter(|mode| r18 || mode.x_restrict() == 0) {
+                @if mode == current.mode {
+                    div { (mode.label()) }
+                } @else {
+                    a href=(format!("/ranking?mode={mode}")) { (mode.label()) }
+                }
+            }
+        }
+        @if current.mode.contents().len() > 1 {
+            div.category.modes {
+                @for content in current.mode.contents() {
+                    @if *content == current.content {
+                        div { (content.label()) }
+                    } @else {
+                        @let link = RankingRequest { content: *content, date: None, ..*current };
+                        a href=(format!("/ranking?{}", link.query())) { (content.label()) }
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub fn ranking(
     client: &ureq::Agent,
     query: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
-    let date = query.get_param("date");
-    let page = get_param_or_num!(query, "p", 1);
-    let ranking = fetch_ranking(client, date.as_ref(), page)?;
+    let request = RankingRequest::try_from(query)?;
+    let show_r18 = !policy.safe_only && shows_r18(query);
+    check_mode(request.mode, policy, show_r18)?;
+
+    let ranking = fetch_ranking(client, &request)?;
+    let blocked_users = get_blocked_userids(query);
+    /* Without a date the feed keeps following the latest ranking, R-18 modes have none */
+    let feed = (request.mode.x_restrict() == 0).then(|| render_alt_ranking(&request));
+    /* Further pages stay on the day that was shown, even when none was asked for */
+    let request = RankingRequest {
+        date: parse_date(&ranking.date),
+        ..request
+    };
+    let day = |date: &Option<String>| {
+        date.as_deref().and_then(parse_date).map(|date| RankingRequest {
+            date: Some(date),
+            page: 1,
+            ..request
+        })
+    };
+    let (previous, next) = (day(&ranking.prev_date), day(&ranking.next_date));
 
     let document = document(
         "Pixiv Proxy",
         html! {
             h1 { "Pixiv Proxy" }
             (render_options("", SearchRating::Safe, SearchOrder::DateDescending, SearchMode::TagsPartial))
-            ul.search.ranking {
-                @for item in ranking.contents {
-                    @let url = format!("/artworks/{}", item.illust_id);
-                    li {
-                        div {
-                            a href=(&url) {
-                                @let (width, height) = util::scale_by_aspect_ratio(item.width, item.height, 200, 400);
-                                @let url = &item.url;
-                                img src=(&url) width=(width) height=(height) alt="";
+            (render_modes(&request, show_r18))
+            @if let Some(feed) = &feed {
+                (feed)
+            }
+            div.category.dates {
+                @if let Some(previous) = &previous {
+                    a href=(format!("/ranking?{}", previous.query())) { "« " (format_date(previous.date)) }
+                }
+                a href=(format!("/ranking/calendar?{}", request.query())) { (format_date(request.date)) }
+                @if let Some(next) = &next {
+                    a href=(format!("/ranking?{}", next.query())) { (format_date(next.date)) " »" }
+                }
+            }
+            (render_grid(&ranking.contents, &blocked_users, policy, None))
+            @let format = format!("/ranking?{}&p=", request.query());
+            (render_nav(request.page, ranking.rank_total, 50, &format))
+        },
+        None,
+    );
+
+    Ok(rouille::Response::html(document.into_string()))
+}
+
+fn format_date(date: Option<NaiveDate>) -> String {
+    date.map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/* Rankings go back to the launch of the daily ranking */
+const FIRST_RANKING: NaiveDate = NaiveDate::from_ymd_opt(2007, 9, 13).unwrap();
+
+/* A month of rankings, days whose ranking is in the response cache show their #1 */
+pub fn calendar(
+    cache: Option<&ResponseCache>,
+    query: &rouille::Request,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
+    let request = RankingRequest::try_from(query)?;
+    let show_r18 = !policy.safe_only && shows_r18(query);
+    check_mode(request.mode, policy, show_r18)?;
+    let blocked_users = get_blocked_userids(query);
+
+    /* Today's ranking is published a day later */
+    let latest = Utc::now().date_naive().pred_opt().unwrap_or(FIRST_RANKING);
+    let shown = request.date.unwrap_or(latest).clamp(FIRST_RANKING, latest);
+    let first = shown.with_day(1).unwrap_or(shown);
+    let days = month_days(first);
+
+    let month = |date: NaiveDate| RankingRequest {
+        date: Some(date),
+        page: 1,
+        ..request
+    };
+    let previous = first
+        .checked_sub_months(Months::new(1))
+        .filter(|_| first > FIRST_RANKING)
+        .map(month);
+    let next = first
+        .checked_add_months(Months::new(1))
+        .filter(|next| *next <= latest)
+        .map(month);
+
+    /* Monday first, padded up to the first day */
+    let offset = first.weekday().num_days_from_monday() as usize;
+    let cells = std::iter::repeat_n(None, offset)
+        .chain(days.into_iter().map(Some))
+        .collect::<Vec<_>>();
+
+    let document = document(
+        &format!("{} ranking {}", request.mode.label(), first.format("%B %Y")),
+        html! {
+            h1 { (request.mode.label()) " ranking " (first.format("%B %Y")) }
+            div.category.dates {
+                @if let Some(previous) = &previous {
+                    a href=(format!("/ranking/calendar?{}", previous.query())) { "« Previous month" }
+                }
+                @if let Some(next) = &next {
+                    a href=(format!("/ranking/calendar?{}", next.query())) { "Next month »" }
+                }
+            }
+            table.calendar {
+                tr {
+                    @for weekday in ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"] {
+                        th { (weekday) }
+                    }
+                }
+                @for week in cells.chunks(7) {
+                    tr {
+                        @for cell in week {
+                            td {
+                                @if let Some(date) = cell.filter(|date| (FIRST_RANKING..=latest).contains(date)) {
+                                    @let day = month(date);
+                                    @let link = format!("/ranking?{}", day.query());
+                                    a href=(&link) { (date.day()) }
+                                    @let top = cache
+                                        .and_then(|cache| cached_ranking(cache, &day))
+                                        .and_then(|ranking| ranking.contents.into_iter().next())
+                                        .filter(|item| !blocked_users.contains(&item.user_id) && !policy.hides(item));
+                                    @if let Some(item) = top {
+                                        a href=(&link) {
+                                            @let (width, height) = util::scale_by_aspect_ratio(item.width, item.height, 80, 80);
+                                            img src=(&item.url) width=(width) height=(height) alt=(&item.title) title=(&item.title);
+                                        }
+                                    }
+                                } @else if let Some(date) = cell {
+                                    (date.day())
+                                }
                             }
                         }
-                        a href=(&url) { (&item.title) }
                     }
                 }
             }
-            @let format = format!("?date={}&p=", ranking.date);
-            (render_nav(page, ranking.rank_total, 50, &format))
         },
         None,
     );
 
     Ok(rouille::Response::html(document.into_string()))
 }
+
+/* All days of the month starting at `first` */
+fn month_days(first: NaiveDate) -> Vec<NaiveDate> {
+    first
+        .iter_days()
+        .take_while(|day| day.month() == first.month())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ranking::RankingContent;
+
+    #[test]
+    fn r18_modes_need_both_settings() {
+        let open = ContentPolicy::default();
+        let safe = ContentPolicy {
+            safe_only: true,
+            ..Default::default()
+        };
+
+        assert!(check_mode(RankingMode::Weekly, &safe, false).is_ok());
+        assert!(check_mode(RankingMode::DailyR18, &open, false).is_err());
+        assert!(check_mode(RankingMode::R18g, &open, true).is_ok());
+        assert!(check_mode(RankingMode::DailyR18Ai, &safe, true).is_err());
+        assert_eq!("male_r18".parse(), Ok(RankingMode::MaleR18));
+        assert!("r18".parse::<RankingMode>().is_err());
+    }
+
+    #[test]
+    fn content_follows_the_mode() {
+        let parse = |url: &str| {
+            RankingRequest::try_from(&rouille::Request::fake_http("GET", url, vec![], vec![]))
+                .unwrap()
+        };
+
+        let request = parse("/ranking?mode=weekly&content=ugoira&p=2");
+        assert_eq!(request.content, RankingContent::Ugoira);
+        assert_eq!(request.page, 2);
+        assert_eq!(request.query(), "mode=weekly&content=ugoira");
+
+        /* The monthly ranking has no ugoira, the male ranking no content types at all */
+        assert_eq!(parse("/ranking?mode=monthly&content=ugoira").content, RankingContent::All);
+        assert_eq!(parse("/ranking?mode=male&content=manga").query(), "mode=male");
+        assert_eq!(parse("/?date=20240101").query(), "mode=daily&date=20240101");
+        assert_eq!(parse("/?date=2024-01-01").query(), "mode=daily");
+
+        /* Unknown modes never end up in links or feeds */
+        let unknown = rouille::Request::fake_http("GET", "/ranking?mode=x", vec![], vec![]);
+        assert!(RankingRequest::try_from(&unknown).is_err());
+    }
+
+    #[test]
+    fn months_have_their_days() {
+        let days = |year, month| month_days(NaiveDate::from_ymd_opt(year, month, 1).unwrap());
+        assert_eq!(days(2024, 2).len(), 29);
+        assert_eq!(days(2023, 2).len(), 28);
+        assert_eq!(days(2024, 12).last(), NaiveDate::from_ymd_opt(2024, 12, 31).as_ref());
+    }
+}
@@ -1,12 +1,63 @@
-use crate::api::error::ApiError;
+use maud::html;
+use ureq::http::Uri;
 
-pub fn jump(path: &rouille::Request) -> Result<rouille::Response, ApiError> {
+use crate::{
+    api::error::ApiError, config::JumpConfig, render::document::document,
+    util::{encode_url, is_local},
+};
+
+pub fn jump(path: &rouille::Request, config: &JumpConfig) -> Result<rouille::Response, ApiError> {
     let destination = path.raw_query_string();
     let destination = percent_encoding::percent_decode_str(destination)
         .decode_utf8_lossy()
         .into_owned();
 
-    Ok(rouille::Response::redirect_301(destination))
+    /* Links into this instance need no confirmation */
+    if is_local(&destination) {
+        return Ok(rouille::Response::redirect_302(encode_url(&destination)));
+    }
+
+    let uri = encode_url(destination.trim())
+        .parse::<Uri>()
+        .map_err(|_| ApiError::External(400, "Invalid destination".into()))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        return Err(ApiError::External(
+            400,
+            "Only http and https links can be followed".into(),
+        ));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| ApiError::External(400, "Invalid destination".into()))?
+        .to_ascii_lowercase();
+
+    if config.denies(&host) {
+        return Err(ApiError::External(
+            403,
+            format!("Links to {host} are blocked on this instance").into(),
+        ));
+    }
+    if config.allows(&host) {
+        return Ok(rouille::Response::redirect_302(uri.to_string()));
+    }
+
+    let document = document(
+        "Leaving this instance",
+        html! {
+            div.jump {
+                h1 { "Leaving this instance" }
+                p { "This link takes you to " strong { (&host) } ":" }
+                code.jump__url { (uri.to_string()) }
+                a.jump__continue href=(uri.to_string()) rel="noopener noreferrer nofollow" {
+                    "Continue to " (&host)
+                }
+                p { a href="/" { "Go home" } }
+            }
+        },
+        None,
+    );
+
+    Ok(rouille::Response::html(document.into_string()))
 }
 
 pub fn legacy_illust(query: &rouille::Request) -> Result<rouille::Response, ApiError> {
@@ -29,3 +80,60 @@ pub fn fanbox(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiErr
 
     Ok(rouille::Response::redirect_301(destination.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jump_to(query: &str) -> Result<rouille::Response, ApiError> {
+        let url = format!("/jump.php?{query}");
+        let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+        let config = JumpConfig {
+            allow: vec!["example.org".into()],
+            deny: vec![],
+        };
+        jump(&request, &config)
+    }
+
+    fn location(response: &rouille::Response) -> Option<&str> {
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Location")
+            .map(|(_, value)| value.as_ref())
+    }
+
+    #[test]
+    fn only_plain_paths_redirect_directly() {
+        let local = jump_to("/artworks/1").unwrap();
+        assert_eq!(local.status_code, 302);
+        assert_eq!(location(&local), Some("/artworks/1"));
+
+        for query in ["/%5Cevil.com", "/%09/evil.com", "//evil.com", "/%0A/evil.com"] {
+            let decoded = percent_encoding::percent_decode_str(query).decode_utf8_lossy();
+            assert!(!is_local(&decoded), "{query}");
+            if let Ok(response) = jump_to(query) {
+                assert_eq!(response.status_code, 200, "{query}");
+                assert_eq!(location(&response), None, "{query}");
+            }
+        }
+    }
+
+    #[test]
+    fn redirects_are_percent_encoded() {
+        let external = jump_to("https%3A%2F%2Fexample.org%2Ftags%2F%E5%8E%9F%E7%A5%9E%20x");
+        let external = external.unwrap();
+        assert_eq!(
+            location(&external),
+            Some("https://example.org/tags/%E5%8E%9F%E7%A5%9E%20x")
+        );
+        let local = jump_to("/tags/%E5%8E%9F%E7%A5%9E/artworks").unwrap();
+        assert_eq!(location(&local), Some("/tags/%E5%8E%9F%E7%A5%9E/artworks"));
+
+        /* What the server sends has to survive as a header */
+        for response in [external, local] {
+            let location = location(&response).unwrap();
+            assert!(tiny_http::Header::from_bytes("Location", location).is_ok());
+        }
+    }
+}
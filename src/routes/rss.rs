@@ -1,12 +1,16 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
 use maud::html;
 use std::str::FromStr;
 
 use crate::{
     api::{
+        common::PixivSearchResult,
         error::ApiError,
+        ranking::{fetch_ranking, RankingContent, RankingItem, RankingRequest},
         search::{fetch_search, SearchMode, SearchOrder, SearchRating, SearchRequest},
         user::{fetch_user_illust_ids, fetch_user_illustrations},
     },
+    config::ContentPolicy,
     get_param_or_enum,
     render::datetime::DateTimeWrapper,
 };
@@ -15,20 +19,197 @@ pub struct RssConfig {
     pub host: String,
 }
 
+/* What an item shows, whichever listing it came from */
+struct FeedItem<'a> {
+    id: u64,
+    title: String,
+    /* The date in the image paths, without it only the thumbnail is shown */
+    image_date: Option<DateTime<FixedOffset>>,
+    illust_type: u64,
+    page_count: u64,
+    thumbnail: &'a str,
+    published: Option<DateTime<FixedOffset>>,
+}
+
+impl<'a> From<&'a PixivSearchResult> for FeedItem<'a> {
+    fn from(s: &'a PixivSearchResult) -> Self {
+        Self {
+            id: s.id,
+            title: s.title.clone(),
+            image_date: DateTime::parse_from_rfc3339(&s.update_date).ok(),
+            illust_type: s.illust_type.into(),
+            page_count: s.page_count.into(),
+            thumbnail: &s.url,
+            published: DateTime::parse_from_rfc3339(&s.create_date).ok(),
+        }
+    }
+}
+
+impl<'a> From<&'a RankingItem> for FeedItem<'a> {
+    fn from(s: &'a RankingItem) -> Self {
+        Self {
+            id: s.illust_id.into(),
+            title: format!("#{} {}", s.rank, s.title),
+            image_date: image_date(&s.url),
+            illust_type: s.illust_type,
+            page_count: s.illust_page_count,
+            thumbnail: &s.url,
+            published: DateTime::from_timestamp(s.illust_upload_timestamp as i64, 0)
+                .map(|date| date.fixed_offset()),
+        }
+    }
+}
+
+/* Image paths carry the update time in Japanese time, e.g. img/2024/01/31/00/00/00/ */
+fn image_date(url: &str) -> Option<DateTime<FixedOffset>> {
+    let (_, path) = url.split_once("/img/")?;
+    let date = NaiveDateTime::parse_from_str(path.get(..19)?, "%Y/%m/%d/%H/%M/%S").ok()?;
+    let japan = FixedOffset::east_opt(9 * 3600)?;
+    date.and_local_timezone(japan).single()
+}
+
+fn render_item(s: FeedItem, config: &RssConfig) -> ::rss::Item {
+    let link = format!("{}/artworks/{}", config.host, s.id);
+    let guid = ::rss::GuidBuilder::default()
+        .value(link.clone())
+        .permalink(true)
+        .build();
+    let description = match s.image_date {
+        Some(date) => {
+            let img_base = format!(
+                "{}/imageproxy/img-master/img/{}/{}",
+                config.host,
+                date.format("%Y/%m/%d/%H/%M/%S"),
+                s.id
+            );
+            html!(
+                h1 { (&s.title) }
+                p { (DateTimeWrapper(date.into())) }
+                @match s.illust_type {
+                    2 => {
+                        img src=(format!("{}_master1200.jpg", img_base)) alt=(s.id);
+                    }
+                    _ => {
+                        @for i in 0..s.page_count {
+                            img src=(format!("{}_p{}_master1200.jpg", img_base, i)) alt=(i);
+                        }
+                    }
+                }
+            )
+        }
+        None => {
+            html!(
+                @let url = format!(
+                    "{}{}",
+                    config.host,
+                    s.thumbnail
+                );
+                img src=(url) width="250" height="250" alt=(s.id);
+            )
+        }
+    };
+    ::rss::ItemBuilder::default()
+        .title(Some(s.title))
+        .link(Some(link))
+        .guid(Some(guid))
+        .description(Some(description.into_string()))
+        .pub_date(s.published.map(|date| date.to_rfc2822()))
+        .build()
+}
+
+/* Feed of a ranking page, e.g. the daily top 50 */
+fn ranking_feed(
+    client: &ureq::Agent,
+    query: &rouille::Request,
+    config: &RssConfig,
+    policy: &ContentPolicy,
+) -> Result<(String, String, Vec<::rss::Item>), ApiError> {
+    let request = RankingRequest::try_from(query)?;
+    /* Feed readers send no cookies, so nobody can have opted in to the R-18 modes */
+    if request.mode.x_restrict() > 0 {
+        return Err(if policy.safe_only {
+            ContentPolicy::blocked()
+        } else {
+            ApiError::External(403, "R-18 rankings have no feeds".into())
+        });
+    }
+    let ranking = fetch_ranking(client, &request)?;
+
+    let mut title = format!("{} ranking", request.mode.label());
+    if request.content != RankingContent::All {
+        title = format!("{title}, {}", request.content.label());
+    }
+    if let Some(date) = request.date {
+        title = format!("{title} of {}", date.format("%Y-%m-%d"));
+    }
+    let self_url = format!("{}/rss?qtype=ranking&{}", config.host, request.query());
+    let items = ranking
+        .contents
+        .iter()
+        .filter(|s| !policy.hides(*s))
+        .map(|s| render_item(s.into(), config))
+        .collect();
+
+    Ok((title, self_url, items))
+}
+
 pub fn rss(
     client: &ureq::Agent,
     query: &rouille::Request,
     config: &RssConfig,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
+    let qtype = query
+        .get_param("qtype")
+        .ok_or_else(|| ApiError::External(400, "Missing Parameter".into()))?;
+    let (title, self_url, items) = if qtype == "ranking" {
+        ranking_feed(client, query, config, policy)?
+    } else {
+        search_feed(client, query, &qtype, config, policy)?
+    };
+
+    let content = ::rss::ChannelBuilder::default()
+        .title(title)
+        .link(self_url)
+        .items(items)
+        .description("Pixiv RSS")
+        .build();
+
+    let content = content.to_string();
+
+    Ok(rouille::Response {
+        status_code: 200,
+        headers: vec![(
+            "Content-Type".into(),
+            "application/rss+xml; charset=utf-8".into(),
+        )],
+        data: rouille::ResponseBody::from_string(content),
+        upgrade: None,
+    })
+}
+
+/* Feed of an author's works or of a search */
+fn search_feed(
+    client: &ureq::Agent,
+    query: &rouille::Request,
+    qtype: &str,
+    config: &RssConfig,
+    policy: &ContentPolicy,
+) -> Result<(String, String, Vec<::rss::Item>), ApiError> {
     let words = query
         .get_param("q")
         .ok_or_else(|| ApiError::External(403, "Missing Parameter".into()))?;
-    let qtype = query.get_param("qtype").unwrap();
-    let rating = get_param_or_enum!(query, "rating", SearchRating, SearchRating::All);
+    let rating = match policy.safe_only {
+        true => SearchRating::Safe,
+        false => get_param_or_enum!(query, "rating", SearchRating, SearchRating::All),
+    };
     let mode = get_param_or_enum!(query, "mode", SearchMode, SearchMode::TagsPerfect);
-    let page = match qtype.as_str() {
+    let page = match qtype {
         "author" => {
-            let user_id = words.parse::<u64>().unwrap();
+            let user_id = super::users::parse_user_id(&words)?;
+            if policy.hides_user(user_id) {
+                return Err(ContentPolicy::blocked());
+            }
             let mut ids = fetch_user_illust_ids(client, user_id)?;
             ids.truncate(60);
             fetch_user_illustrations(client, user_id, &ids)?
@@ -45,65 +226,13 @@ pub fn rss(
         }
     };
 
-    let items: Vec<::rss::Item> = page
+    let items = page
         .iter()
-        .map(|s| {
-            let link = format!("{}/artworks/{}", config.host, s.id);
-            let guid = ::rss::GuidBuilder::default()
-                .value(link.clone())
-                .permalink(true)
-                .build();
-            let date = chrono::DateTime::parse_from_rfc3339(&s.update_date);
-            let description = match date {
-                Ok(date) => {
-                    let img_base = format!(
-                        "{}/imageproxy/img-master/img/{}/{}",
-                        config.host,
-                        date.format("%Y/%m/%d/%H/%M/%S"),
-                        s.id
-                    );
-                    html!(
-                        h1 { (&s.title) }
-                        p { (DateTimeWrapper(date.into())) }
-                        @match s.illust_type {
-                            2 => {
-                                img src=(format!("{}_master1200.jpg", img_base)) alt=(s.id);
-                            }
-                            _ => {
-                                @for i in 0..s.page_count {
-                                    img src=(format!("{}_p{}_master1200.jpg", img_base, i)) alt=(i);
-                                }
-                            }
-                        }
-                    )
-                }
-                Err(_) => {
-                    html!(
-                        @let url = format!(
-                            "{}{}",
-                            config.host,
-                            &s.url
-                        );
-                        img src=(url) width="250" height="250" alt=(s.id);
-                    )
-                }
-            };
-            let create_date = chrono::DateTime::parse_from_rfc3339(&s.create_date);
-            let rfc2822 = match create_date {
-                Ok(date) => Some(date.to_rfc2822()),
-                Err(_) => None,
-            };
-            ::rss::ItemBuilder::default()
-                .title(Some(s.title.clone()))
-                .link(Some(link))
-                .guid(Some(guid))
-                .description(Some(description.into_string()))
-                .pub_date(rfc2822)
-                .build()
-        })
+        .filter(|s| !policy.hides(*s))
+        .map(|s| render_item(s.into(), config))
         .collect();
 
-    let self_url = match qtype.as_str() {
+    let self_url = match qtype {
         "author" => {
             format!("{}/rss?type=author&q={}", config.host, words)
         }
@@ -115,22 +244,58 @@ pub fn rss(
         }
     };
 
-    let content = ::rss::ChannelBuilder::default()
-        .title(&words)
-        .link(self_url)
-        .items(items)
-        .description("Pixiv RSS")
-        .build();
+    Ok((words, self_url, items))
+}
 
-    let content = content.to_string();
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
 
-    Ok(rouille::Response {
-        status_code: 200,
-        headers: vec![(
-            "Content-Type".into(),
-            "application/rss+xml; charset=utf-8".into(),
-        )],
-        data: rouille::ResponseBody::from_string(content),
-        upgrade: None,
-    })
+    use super::*;
+
+    proptest! {
+        /* Feed queries come from feed readers and are never checked by a browser first */
+        #[test]
+        fn queries_never_panic(
+            query in prop::collection::vec(("[a-z_]{1,8}", "[^&#]{0,16}"), 0..6),
+        ) {
+            let query = query
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join("&");
+            let url = format!("/rss?{query}");
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+
+            let _ = SearchRequest::from(&request);
+            let _ = RankingRequest::try_from(&request).map(|request| request.query());
+            let _ = get_param_or_enum!(request, "rating", SearchRating, SearchRating::All);
+            let _ = get_param_or_enum!(request, "mode", SearchMode, SearchMode::TagsPerfect);
+            let _ = crate::get_param_or_num!(request, "p", 1);
+        }
+    }
+
+    #[test]
+    fn r18_rankings_have_no_feeds() {
+        let client = ureq::Agent::new_with_defaults();
+        let feed = |url: &str, policy: &ContentPolicy| {
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            let config = RssConfig { host: String::new() };
+            ranking_feed(&client, &request, &config, policy).map(|_| ()).unwrap_err()
+        };
+
+        let policy = ContentPolicy::default();
+        assert_eq!(feed("/rss?qtype=ranking&mode=daily_r18", &policy).status_code(), 403);
+        assert_eq!(feed("/rss?qtype=ranking&mode=r18g", &policy).status_code(), 403);
+        let safe = ContentPolicy { safe_only: true, ..ContentPolicy::default() };
+        assert_eq!(feed("/rss?qtype=ranking&mode=daily_r18", &safe).status_code(), 451);
+    }
+
+    #[test]
+    fn ranking_items_use_the_image_date() {
+        let url = "/imageproxy/c/240x480/img-master/img/2024/01/31/12/30/00/115000000_p0_master1200.jpg";
+        let date = image_date(url).unwrap();
+        assert_eq!(date.to_rfc3339(), "2024-01-31T12:30:00+09:00");
+        assert!(image_date("/imageproxy/img-master/img/2024/x").is_none());
+    }
 }
@@ -3,8 +3,9 @@ use maud::html;
 use crate::{
     api::{
         error::ApiError,
-        search::{fetch_search, SearchRequest},
+        search::{fetch_search, SearchRating, SearchRequest},
     },
+    config::ContentPolicy,
     get_param_or_str,
     render::{datetime::DateTimeWrapper, document::document, nav::render_nav},
     util,
@@ -13,13 +14,20 @@ use crate::{
 pub fn scroll(
     client: &ureq::Agent,
     query: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
     let qtype = get_param_or_str!(query, "qtype", "search");
     let words = get_param_or_str!(query, "q", "");
-    let query = SearchRequest::from(query);
+    let mut query = SearchRequest::from(query);
+    if policy.safe_only {
+        query.rating = SearchRating::Safe;
+    }
     let (data, total) = match &qtype[..] {
         "author" => {
-            let user_id = words.parse::<u64>().unwrap();
+            let user_id = super::users::parse_user_id(&words)?;
+            if policy.hides_user(user_id) {
+                return Err(ContentPolicy::blocked());
+            }
             super::users::fetch_illustrations(client, user_id, query.page, &words, false)?
         }
         _ => {
@@ -34,7 +42,7 @@ pub fn scroll(
             h1 { (words) }
             p { (total) }
             ul.scroll.artworks {
-                @for illust in data.iter() {
+                @for illust in data.iter().filter(|illust| !policy.hides(*illust)) {
                     li {
                         h2 { a href=(format!("/artworks/{}", illust.id)) { (illust.title) } }
 
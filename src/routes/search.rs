@@ -3,12 +3,14 @@ use maud::html;
 use crate::{
     api::{
         error::ApiError,
-        search::{fetch_search, SearchRequest},
+        search::{fetch_search, SearchRating, SearchRequest},
     },
+    config::ContentPolicy,
     render::{
         alt::render_alt_search, document::document, grid::{render_grid, render_grid_contents}, nav::render_nav,
         search::render_options,
     },
+    security,
     settings::get_blocked_userids,
 };
 
@@ -16,35 +18,41 @@ pub fn tags(
     client: &ureq::Agent,
     tags: &str,
     request: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
-    render_search(client, tags, request)
+    render_search(client, tags, request, policy)
 }
 
 pub fn query_search(
     client: &ureq::Agent,
     request: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
     let words = request
         .get_param("q")
         .ok_or_else(|| ApiError::External(403, "No query".into()))?;
-    render_search(client, &words, request)
+    render_search(client, &words, request, policy)
 }
 
 fn render_search(
     client: &ureq::Agent,
     tags: &str,
     request: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
     let blocked_set = get_blocked_userids(request);
 
-    let query = SearchRequest::from(request);
+    let mut query = SearchRequest::from(request);
+    if policy.safe_only {
+        query.rating = SearchRating::Safe;
+    }
     let search = fetch_search(client, tags, &query)?;
 
     let format = format!("/search?q={}&order={}&mode={}&s_mode={}&p=", tags, query.order, query.rating, query.mode);
-    let next_page_ajax = format!("{}{}&ajax=", format, query.page + 1);
+    let _next_page_ajax = format!("{}{}&ajax=", format, query.page + 1);
     // let load_more = Some(html! {
     //     div.load_more {
-    //         button endpoint=(next_page_ajax) onclick="inject(this, true, true)" {
+    //         button endpoint=(next_page_ajax) data-parent {
     //             "Load more..."
     //         }
     //     }
@@ -52,7 +60,7 @@ fn render_search(
 
     if request.get_param("ajax").is_some() {
         let document = html! {
-            (render_grid_contents(&search.illust_manga.data, &blocked_set))
+            (render_grid_contents(&search.illust_manga.data, &blocked_set, policy))
             // @if let Some(load_more) = load_more {
             //     (load_more)
             // }
@@ -67,7 +75,7 @@ fn render_search(
             (&search.illust_manga.total)
             (render_alt_search(tags, &query))
             (render_options(tags, query.rating, query.order, query.mode))
-            (render_grid(&search.illust_manga.data, &blocked_set, None))
+            (render_grid(&search.illust_manga.data, &blocked_set, policy, None))
             @if search.illust_manga.total > 60 {
                 // @if roots.has_next {
                     // (load_more)
@@ -79,7 +87,7 @@ fn render_search(
             }
         },
         Some(html! {
-            script {
+            script nonce=(security::nonce()) {
                 (maud::PreEscaped(include_str!("../dynamic.js")))
             }
         }),
@@ -1,11 +1,17 @@
 use std::{collections::HashSet, io::Read};
 
-use crate::render::document::document;
+use crate::{config::ContentPolicy, render::document::document};
 use maud::html;
 use rouille::input::cookies;
 
 const SEPERATOR: &str = "%7C";
 const BLOCKED_COOKIE: &str = "blocked_users";
+const R18_COOKIE: &str = "show_r18";
+
+/* Visitors have to opt in before R-18 rankings are offered */
+pub fn shows_r18(request: &rouille::Request) -> bool {
+    cookies(request).any(|(k, v)| k == R18_COOKIE && v == "1")
+}
 
 pub fn get_blocked_userids(request: &rouille::Request) -> HashSet<u64> {
     let Some((_, v)) = cookies(request).find(|&(k, _)| k == BLOCKED_COOKIE) else {
@@ -45,8 +51,9 @@ pub fn set_blocked_userids(response: &mut rouille::Response, users: &HashSet<u64
     ));
 }
 
-pub fn index(request: &rouille::Request) -> rouille::Response {
+pub fn index(request: &rouille::Request, policy: &ContentPolicy) -> rouille::Response {
     let blocked_users = get_blocked_userids(request);
+    let show_r18 = shows_r18(request);
 
     let document = document(
         "Settings",
@@ -55,6 +62,20 @@ pub fn index(request: &rouille::Request) -> rouille::Response {
             p { "All settings are stored in Cookies that are stored in your browser." }
             p { "No data is kept on the server after process your requests." }
 
+            @if !policy.safe_only {
+                h2 { "Sensitive Content" }
+                p { "R-18 and R-18G rankings are only listed after you confirm that you are of age and want to see them." }
+                form action="/settings/r18" method="POST" {
+                    @if show_r18 {
+                        input type="hidden" name="show_r18" value="0" { }
+                        input type="submit" value="Hide R-18 rankings" { }
+                    } @else {
+                        input type="hidden" name="show_r18" value="1" { }
+                        input type="submit" value="Show R-18 rankings" { }
+                    }
+                }
+            }
+
             h2 { "Blocked Users" }
             p {
                 "You can either select the \"Block\" Button on a User-Profile or import a list off the format \"12345|23456|34567\" here. The name is currently only used for this settings page."
@@ -167,3 +188,25 @@ pub fn blocked_users_del(request: &rouille::Request) -> rouille::Response {
 
     redirect
 }
+
+pub fn r18_set(request: &rouille::Request) -> rouille::Response {
+    let redirect = request.header("Referer").unwrap_or("/settings").to_string();
+    let mut redirect = rouille::Response::redirect_303(redirect);
+
+    let Some(mut data) = request.data() else {
+        return redirect;
+    };
+
+    let mut form = String::new();
+    let Ok(_) = data.read_to_string(&mut form) else {
+        return redirect;
+    };
+
+    let value = if form == "show_r18=1" { "1" } else { "0" };
+    redirect.headers.push((
+        "Set-Cookie".into(),
+        format!("{R18_COOKIE}={value}; Path=/; Max-Age=31536000").into(),
+    ));
+
+    redirect
+}
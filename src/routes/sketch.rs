@@ -6,19 +6,23 @@ use crate::{
             fetch_tag_wall, fetch_user,
         },
     },
+    config::ContentPolicy,
     render::document::document,
 };
 
 use maud::html;
 
-pub fn sketch_public(client: &ureq::Agent) -> Result<rouille::Response, ApiError> {
+pub fn sketch_public(
+    client: &ureq::Agent,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     let wall = fetch_public_wall(client, None, None)?.data;
 
     let document = document(
         "Sketch",
         html! {
             ul.sketch_wall {
-                @for item in &wall.items {
+                @for item in wall.items.iter().filter(|item| !policy.hides(*item)) {
                     li { (item) }
                 }
             }
@@ -30,14 +34,18 @@ pub fn sketch_public(client: &ureq::Agent) -> Result<rouille::Response, ApiError
     Ok(rouille::Response::html(document.into_string()))
 }
 
-pub fn sketch_tags(client: &ureq::Agent, tag: &str) -> Result<rouille::Response, ApiError> {
+pub fn sketch_tags(
+    client: &ureq::Agent,
+    tag: &str,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     let wall = fetch_tag_wall(client, tag)?.data;
 
     let document = document(
         "Sketch",
         html! {
             ul.sketch_wall {
-                @for item in &wall.items {
+                @for item in wall.items.iter().filter(|item| !policy.hides(*item)) {
                     li { (item) }
                 }
             }
@@ -49,8 +57,15 @@ pub fn sketch_tags(client: &ureq::Agent, tag: &str) -> Result<rouille::Response,
     Ok(rouille::Response::html(document.into_string()))
 }
 
-pub fn sketch_user(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiError> {
+pub fn sketch_user(
+    client: &ureq::Agent,
+    id: u64,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     let user = fetch_user(client, id)?;
+    if policy.hides_user(user.data.pixiv_user_id) {
+        return Err(ContentPolicy::blocked());
+    }
 
     // TODO
     let ajax = serde_json::to_string_pretty(&user).unwrap();
@@ -58,24 +73,42 @@ pub fn sketch_user(client: &ureq::Agent, id: u64) -> Result<rouille::Response, A
     Ok(rouille::Response::html(ajax))
 }
 
-pub fn sketch_item(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiError> {
+pub fn sketch_item(
+    client: &ureq::Agent,
+    id: u64,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     let item = fetch_item(client, id)?;
+    if policy.hides(&item.data) {
+        return Err(ContentPolicy::blocked());
+    }
 
     let ajax = serde_json::to_string_pretty(&item).unwrap();
 
     Ok(rouille::Response::html(ajax))
 }
 
-pub fn sketch_lives(client: &ureq::Agent) -> Result<rouille::Response, ApiError> {
-    let lives = fetch_lives(client, 20, "audience_count")?;
+pub fn sketch_lives(
+    client: &ureq::Agent,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
+    let mut lives = fetch_lives(client, 20, "audience_count")?;
+    lives.data.lives.retain(|live| !policy.hides(live));
 
     let ajax = serde_json::to_string_pretty(&lives).unwrap();
 
     Ok(rouille::Response::html(ajax))
 }
 
-pub fn sketch_impressions(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiError> {
+pub fn sketch_impressions(
+    client: &ureq::Agent,
+    id: u64,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     let impressions = fetch_feedbacks(client, id)?;
+    if policy.hides(&impressions.data.item) {
+        return Err(ContentPolicy::blocked());
+    }
 
     let ajax = serde_json::to_string_pretty(&impressions).unwrap();
 
@@ -1,52 +1,162 @@
-use crate::api::error::ApiError;
+use crate::{
+    api::error::ApiError,
+    config::{ContentPolicy, SizeLimitConfig},
+};
 
 #[cfg(feature = "ugoira")]
-pub fn ugoira(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiError> {
+pub fn ugoira(
+    client: &ureq::Agent,
+    image_client: &ureq::Agent,
+    id: u64,
+    limits: &SizeLimitConfig,
+    policy: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     use crate::api::ugoira::{fetch_ugoira_meta, UgoiraFrame};
     use std::{
         io::BufReader,
         io::{Cursor, Read, Seek, Write},
     };
 
+    /* Why the conversion gave up, only the limits are the visitor's business */
+    #[derive(Clone, Copy)]
+    enum Failure {
+        ArchiveTooLarge,
+        OutputTooLarge,
+        /* A broken or cut off download from pixiv */
+        BadArchive,
+    }
+    impl From<Failure> for ApiError {
+        fn from(failure: Failure) -> Self {
+            match failure {
+                Failure::ArchiveTooLarge => ApiError::External(
+                    413,
+                    "This ugoira is too large to be converted on this instance".into(),
+                ),
+                Failure::OutputTooLarge => ApiError::External(
+                    413,
+                    "The converted ugoira is larger than this instance allows".into(),
+                ),
+                Failure::BadArchive => {
+                    ApiError::External(502, "Failed to read the ugoira archive".into())
+                }
+            }
+        }
+    }
+
+    super::artworks::check_policy(client, id, policy)?;
     let meta = fetch_ugoira_meta(&client, id)?;
+    if meta.frames.len() > limits.ugoira_frames {
+        return Err(ApiError::External(
+            413,
+            format!(
+                "This ugoira has {} frames, this instance converts up to {}",
+                meta.frames.len(),
+                limits.ugoira_frames
+            )
+            .into(),
+        ));
+    }
 
-    let ugoira = client.get(&meta.original_src).call()?;
+    let archive_limit = limits.ugoira_archive * 1024 * 1024;
+    let ugoira = image_client.get(&meta.original_src).call()?;
+    let announced = ugoira
+        .headers()
+        .get("Content-Length")
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if announced.is_some_and(|length| length > archive_limit) {
+        return Err(Failure::ArchiveTooLarge.into());
+    }
     let body = ugoira.into_body();
 
-    let reader: Box<dyn Read + Send> = Box::new(body.into_reader());
+    /* Fails once more than `remaining` bytes went through */
+    struct Limited<R> {
+        inner: R,
+        remaining: u64,
+    }
+    impl<R: Read> Read for Limited<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.inner.read(buf)?;
+            self.remaining = self
+                .remaining
+                .checked_sub(read as u64)
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::FileTooLarge))?;
+            Ok(read)
+        }
+    }
+
+    /* The length header isn't always there, so the stream is capped as well */
+    let reader: Box<dyn Read + Send> = Box::new(Limited {
+        inner: body.into_reader(),
+        remaining: archive_limit,
+    });
     let reader = BufReader::with_capacity(0x4000, reader);
 
+    fn archive_error(error: &std::io::Error) -> Failure {
+        if error.kind() == std::io::ErrorKind::FileTooLarge {
+            Failure::ArchiveTooLarge
+        } else {
+            Failure::BadArchive
+        }
+    }
+
     struct Opaque<'a> {
         reader: BufReader<Box<dyn Read + Send>>,
         file: Option<zip::read::ZipFile<'a, BufReader<Box<dyn Read + Send>>>>,
         writer: Cursor<Vec<u8>>,
+        output_limit: u64,
+        /* Why a callback gave up, errors must not unwind into C */
+        error: Option<Failure>,
     }
     let mut opaque = Opaque {
         reader,
         file: None,
         writer: Cursor::new(Vec::with_capacity(0x100000)),
+        output_limit: limits.ugoira_output * 1024 * 1024,
+        error: None,
     };
 
     unsafe extern "C" fn read(opaque: *mut libc::c_void, ptr: *mut u8, sz: i32) -> i32 {
         let opaque = opaque as *mut Opaque<'_>;
         let slice = std::slice::from_raw_parts_mut(ptr, sz as usize);
-        let file = (*opaque).file.as_mut().unwrap();
-        file.read(slice).unwrap() as i32
+        let Some(file) = (*opaque).file.as_mut() else {
+            return -1;
+        };
+        match file.read(slice) {
+            Ok(read) => read as i32,
+            Err(error) => {
+                (*opaque).error = Some(archive_error(&error));
+                -1
+            }
+        }
     }
     unsafe extern "C" fn next(opaque: *mut libc::c_void) {
         let opaque = opaque as *mut Opaque<'_>;
         let reader = &mut (*opaque).reader;
-        (*opaque).file = Some(
-            zip::read::read_zipfile_from_stream(reader)
-                .unwrap()
-                .unwrap(),
-        );
+        (*opaque).file = match zip::read::read_zipfile_from_stream(reader) {
+            Ok(Some(file)) => Some(file),
+            Ok(None) => None,
+            Err(zip::result::ZipError::Io(error)) => {
+                (*opaque).error = Some(archive_error(&error));
+                None
+            }
+            Err(_) => {
+                (*opaque).error = Some(Failure::BadArchive);
+                None
+            }
+        };
     }
     unsafe extern "C" fn write(opaque: *mut libc::c_void, ptr: *mut u8, sz: i32) -> i32 {
         let opaque = opaque as *mut Opaque<'_>;
         let slice = std::slice::from_raw_parts(ptr, sz as usize);
-        (*opaque).writer.write_all(slice).unwrap();
-        sz
+        let writer = &mut (*opaque).writer;
+        if writer.position() + slice.len() as u64 > (*opaque).output_limit {
+            (*opaque).error = Some(Failure::OutputTooLarge);
+            return -1;
+        }
+        match writer.write_all(slice) {
+            Ok(()) => sz,
+            Err(_) => -1,
+        }
     }
     unsafe extern "C" fn seek(opaque: *mut libc::c_void, offset: i64, whence: i32) -> i64 {
         let opaque = opaque as *mut Opaque<'_>;
@@ -54,9 +164,12 @@ pub fn ugoira(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiErr
             0 => std::io::SeekFrom::Start(offset as u64),
             1 => std::io::SeekFrom::Current(offset),
             2 => std::io::SeekFrom::End(offset),
-            _ => panic!("invalid whence"),
+            _ => return -1,
         };
-        (*opaque).writer.seek(position).unwrap() as i64
+        match (*opaque).writer.seek(position) {
+            Ok(position) => position as i64,
+            Err(_) => -1,
+        }
     }
 
     extern "C" {
@@ -71,6 +184,7 @@ pub fn ugoira(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiErr
         ) -> i32;
     }
 
+    let start = std::time::Instant::now();
     let ret = unsafe {
         convert(
             &mut opaque as *mut Opaque<'_> as *mut libc::c_void,
@@ -82,8 +196,11 @@ pub fn ugoira(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiErr
             meta.frames.len(),
         )
     };
+    crate::metrics::record_ugoira(start.elapsed());
 
-    if ret != 0 {
+    if let Some(error) = opaque.error {
+        Err(error.into())
+    } else if ret != 0 {
         Err(ApiError::Internal("Failed to re-encode image".into()))
     } else {
         Ok(
@@ -94,6 +211,12 @@ pub fn ugoira(client: &ureq::Agent, id: u64) -> Result<rouille::Response, ApiErr
 }
 
 #[cfg(not(feature = "ugoira"))]
-pub fn ugoira(_: &ureq::Agent, _: u64) -> Result<rouille::Response, ApiError> {
+pub fn ugoira(
+    _: &ureq::Agent,
+    _: &ureq::Agent,
+    _: u64,
+    _: &SizeLimitConfig,
+    _: &ContentPolicy,
+) -> Result<rouille::Response, ApiError> {
     Err(ApiError::External(418, "Feature not enabled".into()))
 }
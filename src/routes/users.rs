@@ -1,4 +1,6 @@
-use maud::{html, PreEscaped};
+use std::ops::Range;
+
+use maud::html;
 
 use crate::{
     api::{
@@ -9,8 +11,12 @@ use crate::{
             fetch_user_profile,
         },
     },
+    config::ContentPolicy,
     get_param_or_num, get_param_or_str,
-    render::{alt::render_alt_author, document::document, grid::render_grid, nav::render_nav},
+    render::{
+        alt::render_alt_author, document::document, grid::render_grid, nav::render_nav,
+        sanitize::sanitize,
+    },
 };
 
 use super::settings::get_blocked_userids;
@@ -19,24 +25,30 @@ pub fn artworks(
     client: &ureq::Agent,
     id: u64,
     query: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
-    user(client, id, query, false)
+    user(client, id, query, policy, false)
 }
 
 pub fn bookmarks(
     client: &ureq::Agent,
     id: u64,
     query: &rouille::Request,
+    policy: &ContentPolicy,
 ) -> Result<rouille::Response, ApiError> {
-    user(client, id, query, true)
+    user(client, id, query, policy, true)
 }
 
 fn user(
     client: &ureq::Agent,
     user_id: u64,
     query: &rouille::Request,
+    policy: &ContentPolicy,
     bookmarks: bool,
 ) -> Result<rouille::Response, ApiError> {
+    if policy.hides_user(user_id) {
+        return Err(ContentPolicy::blocked());
+    }
     let blocked_users = get_blocked_userids(query);
 
     let page = get_param_or_num!(query, "p", 1);
@@ -54,7 +66,7 @@ fn user(
                 h1 { (&user.name) }
                 (render_alt_author(user_id, page))
                 @if !user.comment_html.is_empty() {
-                    p { (PreEscaped(&user.comment_html)) }
+                    p { (sanitize(&user.comment_html)) }
                 }
             }
             div.category {
@@ -78,7 +90,7 @@ fn user(
                 }
             }
             div {
-                (render_grid(&elements, &blocked_users, None))
+                (render_grid(&elements, &blocked_users, policy, None))
             }
             @if count > 60 {
                 @let format = if !bookmarks {
@@ -111,20 +123,80 @@ pub fn fetch_illustrations(
     tags: &str,
     bookmarks: bool,
 ) -> Result<(Vec<PixivSearchResult>, usize), ApiError> {
+    let offset = page_offset(page)?;
     if !bookmarks {
         let ids = fetch_user_illust_ids(client, user_id)?;
 
         let count = ids.len();
-        let start = (page - 1) * 60;
-        let end = std::cmp::min(start + 60, count as u32);
-        let slice = &ids[start as usize..end as usize];
+        let slice = &ids[page_range(page, count)?];
 
         let elements = fetch_user_illustrations(client, user_id, slice)?;
 
         Ok((elements, count))
     } else {
-        let bookmarks = fetch_user_bookmarks(client, user_id, tags, (page - 1) * 60, 60)?;
+        let bookmarks = fetch_user_bookmarks(client, user_id, tags, offset, 60)?;
 
         Ok((bookmarks.works, bookmarks.total))
     }
 }
+
+/* Index of the first work on a page of 60, pages start at 1 */
+pub fn page_offset(page: u32) -> Result<u32, ApiError> {
+    page.checked_sub(1)
+        .and_then(|page| page.checked_mul(60))
+        .ok_or_else(|| ApiError::External(400, "Invalid page number".into()))
+}
+
+/* Works on a page, only the first page of a user without works may be empty */
+pub fn page_range(page: u32, count: usize) -> Result<Range<usize>, ApiError> {
+    let start = page_offset(page)? as usize;
+    if start > 0 && start >= count {
+        return Err(ApiError::External(400, "Page out of range".into()));
+    }
+    Ok(start..count.min(start + 60))
+}
+
+/* User ids given as search words, e.g. by /rss?qtype=author */
+pub fn parse_user_id(id: &str) -> Result<u64, ApiError> {
+    id.trim()
+        .parse()
+        .map_err(|_| ApiError::External(400, "Invalid user id".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn pages_stay_in_bounds(page in any::<u32>(), count in 0usize..100_000) {
+            if let Ok(range) = page_range(page, count) {
+                prop_assert!(range.start <= range.end && range.end <= count);
+                prop_assert!(range.len() <= 60);
+            }
+        }
+
+        #[test]
+        fn page_queries_never_panic(page in "[^&#]{0,16}", id in any::<String>()) {
+            let url = format!("/users/1?p={page}");
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            let page = crate::get_param_or_num!(request, "p", 1);
+            let _ = render_nav(page, 100_000, 60, "/?p=");
+            let _ = page_offset(page);
+            let _ = page_range(page, 1000);
+            let _ = parse_user_id(&id);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_pages() {
+        assert!(page_offset(0).is_err());
+        assert!(page_range(0, 10).is_err());
+        assert!(page_range(3, 120).is_err());
+        assert_eq!(page_range(1, 0).unwrap(), 0..0);
+        assert_eq!(page_range(2, 70).unwrap(), 60..70);
+        assert!(parse_user_id("12a").is_err());
+    }
+}
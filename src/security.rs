@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+
+use crate::config::SecurityConfig;
+
+/* Nothing in a proxied image or feed is supposed to run */
+const RESOURCE_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'; sandbox";
+
+thread_local! {
+    /* Nonce of the page this worker is rendering, see server::serve */
+    static NONCE: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/* Picks a fresh script nonce for the request handled on this thread */
+pub fn begin_request() {
+    let mut bytes = [0u8; 16];
+    /* Without randomness there is no nonce, and the policy allows no inline script */
+    let nonce = match getrandom::fill(&mut bytes) {
+        Ok(()) => bytes.iter().map(|byte| format!("{byte:02x}")).collect(),
+        Err(error) => {
+            log::warn!("No script nonce, can't get random bytes: {error}");
+            String::new()
+        }
+    };
+    NONCE.with(|current| *current.borrow_mut() = nonce);
+}
+
+/* Nonce for inline scripts of the current response */
+pub fn nonce() -> String {
+    NONCE.with(|current| current.borrow().clone())
+}
+
+/* Fills in the nonce, or drops the sources that would take it */
+fn page_policy(template: &str, nonce: &str) -> String {
+    if !nonce.is_empty() {
+        return template.replace("{nonce}", nonce);
+    }
+    template
+        .split(';')
+        .map(|directive| {
+            directive
+                .split_whitespace()
+                .filter(|source| !source.contains("{nonce}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|directive| !directive.is_empty())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/* Adds the security headers, pages get the configured CSP, everything else a locked down one */
+pub fn apply(response: rouille::Response, config: &SecurityConfig) -> rouille::Response {
+    let html = response.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("Content-Type") && value.starts_with("text/html")
+    });
+
+    let mut policy = if html {
+        page_policy(&config.content_security_policy, &nonce())
+    } else {
+        RESOURCE_POLICY.to_owned()
+    };
+    if !config.frame_ancestors.is_empty() && !policy.is_empty() {
+        policy = format!("{policy}; frame-ancestors {}", config.frame_ancestors);
+    }
+
+    let mut headers = vec![
+        ("Content-Security-Policy", policy),
+        ("X-Content-Type-Options", "nosniff".to_owned()),
+        ("Referrer-Policy", config.referrer_policy.clone()),
+        ("Permissions-Policy", config.permissions_policy.clone()),
+    ];
+    /* For browsers that don't know frame-ancestors */
+    match config.frame_ancestors.as_str() {
+        "'none'" => headers.push(("X-Frame-Options", "DENY".to_owned())),
+        "'self'" => headers.push(("X-Frame-Options", "SAMEORIGIN".to_owned())),
+        _ => {}
+    }
+
+    headers
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .fold(response, |response, (name, value)| {
+            response.with_unique_header(name, value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(response: &'a rouille::Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_ref())
+    }
+
+    #[test]
+    fn pages_get_a_nonce() {
+        begin_request();
+        let nonce = nonce();
+        assert_eq!(nonce.len(), 32);
+
+        let config = SecurityConfig::default();
+        let page = apply(rouille::Response::html("<p></p>"), &config);
+        let policy = header(&page, "Content-Security-Policy").unwrap();
+        assert!(policy.contains(&format!("'nonce-{nonce}'")));
+        assert!(policy.ends_with("frame-ancestors 'none'"));
+        assert_eq!(header(&page, "Referrer-Policy"), Some("no-referrer"));
+        assert_eq!(header(&page, "X-Frame-Options"), Some("DENY"));
+
+        let image = apply(rouille::Response::from_data("image/png", vec![]), &config);
+        let policy = header(&image, "Content-Security-Policy").unwrap();
+        assert!(policy.starts_with(RESOURCE_POLICY));
+        assert_eq!(header(&image, "X-Content-Type-Options"), Some("nosniff"));
+    }
+
+    #[test]
+    fn no_nonce_blocks_inline_scripts() {
+        let template = "default-src 'none'; script-src 'self' 'nonce-{nonce}'; img-src 'self'";
+        assert_eq!(
+            page_policy(template, ""),
+            "default-src 'none'; script-src 'self'; img-src 'self'"
+        );
+        assert_eq!(page_policy("script-src 'nonce-{nonce}';", ""), "script-src");
+        assert!(page_policy(template, "ab").contains("'nonce-ab'"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    error::Error,
+    io::Read,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, TrySendError},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+use crate::{config::ServerConfig, logging, metrics, tls};
+
+/* Only the settings form posts anything, and it is tiny */
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/* Clients on the Unix socket have no address, the reverse proxy in front is local */
+const UNIX_PEER: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/* The listening side, rouille only knows plain TCP */
+struct Listener {
+    server: tiny_http::Server,
+    /* Terminates TLS in front of the server when HTTPS is served */
+    tls: Option<tls::Terminator>,
+}
+
+/* Requests waiting in the queue and being handled, exported as metrics */
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Load {
+    pub queued: usize,
+    pub busy: usize,
+}
+
+pub fn load() -> Load {
+    Load {
+        queued: QUEUED.load(Ordering::Relaxed),
+        busy: BUSY.load(Ordering::Relaxed),
+    }
+}
+
+/* Serves `handler` on the configured TCP port or Unix socket until SIGTERM or SIGINT */
+pub fn serve<F>(config: &ServerConfig, handler: F) -> Result<(), BoxError>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    let Listener { server, mut tls } = listen(config)?;
+    let https = tls.is_some();
+    let server = Arc::new(server);
+    handle_signals(Arc::downgrade(&server))?;
+
+    let handler = Arc::new(handler);
+    let (queue, requests) = mpsc::sync_channel::<tiny_http::Request>(config.queue);
+    let requests = Arc::new(Mutex::new(requests));
+    /* Workers see each request through, so per-request state can live in thread locals */
+    let workers = (0..config.workers)
+        .map(|_| {
+            let handler = handler.clone();
+            let requests = requests.clone();
+            thread::spawn(move || work(&requests, https, &*handler))
+        })
+        .collect::<Vec<_>>();
+
+    /* Ends once a signal unblocks the server */
+    for request in server.incoming_requests() {
+        QUEUED.fetch_add(1, Ordering::Relaxed);
+        if let Err(TrySendError::Full(request) | TrySendError::Disconnected(request)) =
+            queue.try_send(request)
+        {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
+            reject(request);
+        }
+    }
+
+    /* Closing the listeners first, then letting the workers empty the queue */
+    if let Some(tls) = &mut tls {
+        tls.stop_accepting();
+    }
+    drop(server);
+    drop(queue);
+    let load = load();
+    log::info!("Shutting down, waiting for {} requests", load.queued + load.busy);
+
+    let deadline = Instant::now() + Duration::from_secs(config.shutdown_timeout);
+    while workers.iter().any(|worker| !worker.is_finished()) {
+        if Instant::now() >= deadline {
+            log::warn!(
+                "Requests still running after {}s, exiting anyway",
+                config.shutdown_timeout
+            );
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    /* Responses still have to make it through the TLS connections */
+    if let Some(tls) = &tls {
+        tls.drain(deadline);
+    }
+
+    Ok(())
+}
+
+/* The first signal starts the shutdown, a second one exits right away */
+fn handle_signals(server: Weak<tiny_http::Server>) -> Result<(), BoxError> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if signals.next().is_some() {
+            if let Some(server) = server.upgrade() {
+                server.unblock();
+            }
+        }
+        if signals.next().is_some() {
+            log::warn!("Second signal, exiting without waiting for requests");
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+fn work<F>(requests: &Mutex<mpsc::Receiver<tiny_http::Request>>, https: bool, handler: &F)
+where
+    F: Fn(&rouille::Request) -> rouille::Response,
+{
+    loop {
+        /* The lock is only held while waiting, not while handling */
+        let request = requests.lock().unwrap().recv();
+        let Ok(request) = request else {
+            return;
+        };
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+        BUSY.fetch_add(1, Ordering::Relaxed);
+        process(request, https, handler);
+        BUSY.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/* Answered right away so clients and load balancers can back off or go elsewhere */
+fn reject(request: tiny_http::Request) {
+    let url = request.url().to_owned();
+    metrics::record_request(&url, 503, Duration::ZERO);
+    logging::access(request.method().as_str(), &url, 503, Duration::ZERO);
+
+    let response = rouille::Response::text("The server is overloaded, please try again shortly")
+        .with_status_code(503)
+        .with_unique_header("Retry-After", "5");
+    respond(request, response);
+}
+
+fn listen(config: &ServerConfig) -> Result<Listener, BoxError> {
+    if let Some(path) = &config.unix_socket {
+        remove_stale_socket(path)?;
+        let server = tiny_http::Server::http_unix(path)?;
+        if let Some(mode) = config.unix_socket_mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        log::info!("Listening on {}", path.display());
+        return Ok(Listener { server, tls: None });
+    }
+
+    let address = format!("{}:{}", config.bind, config.port);
+    let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+        let server = tiny_http::Server::http(&address)?;
+        log::info!("Listening on http://{address}");
+        return Ok(Listener { server, tls: None });
+    };
+
+    /* TLS is terminated in front of a loopback listener only the terminator talks to */
+    let server = tiny_http::Server::http("127.0.0.1:0")?;
+    let backend = server
+        .server_addr()
+        .to_ip()
+        .ok_or("the internal listener has no address")?;
+    let tls = tls::terminate(&address, backend, cert, key, config.tls_connections)?;
+    log::info!("Listening on https://{address}");
+    Ok(Listener {
+        server,
+        tls: Some(tls),
+    })
+}
+
+/* A socket left over from an earlier run blocks binding, anything else is not ours to delete */
+fn remove_stale_socket(path: &std::path::Path) -> Result<(), BoxError> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn process<F>(mut request: tiny_http::Request, https: bool, handler: &F)
+where
+    F: Fn(&rouille::Request) -> rouille::Response,
+{
+    let remote = request
+        .remote_addr()
+        .map_or(UNIX_PEER, |address| tls::client_addr(*address));
+
+    let mut data = Vec::new();
+    let read = request
+        .as_reader()
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_end(&mut data);
+    if read.is_err() {
+        return respond(
+            request,
+            rouille::Response::text("Bad Request").with_status_code(400),
+        );
+    }
+    if data.len() as u64 > MAX_BODY_SIZE {
+        return respond(
+            request,
+            rouille::Response::text("Payload Too Large").with_status_code(413),
+        );
+    }
+
+    let rouille_request = convert_request(&request, remote, https, data);
+    let response = panic::catch_unwind(AssertUnwindSafe(|| handler(&rouille_request)))
+        .unwrap_or_else(|_| {
+            rouille::Response::html(
+                "<h1>Internal Server Error</h1><p>An internal error has occurred on the server.</p>",
+            )
+            .with_status_code(500)
+        });
+    respond(request, response);
+}
+
+fn convert_request(
+    request: &tiny_http::Request,
+    remote: SocketAddr,
+    https: bool,
+    data: Vec<u8>,
+) -> rouille::Request {
+    let headers = request
+        .headers()
+        .iter()
+        .map(|header| (header.field.to_string(), header.value.to_string()))
+        .collect();
+    let method = request.method().as_str();
+    let url = request.url();
+
+    if https {
+        rouille::Request::fake_https_from(remote, method, url, headers, data)
+    } else {
+        rouille::Request::fake_http_from(remote, method, url, headers, data)
+    }
+}
+
+fn respond(request: tiny_http::Request, response: rouille::Response) {
+    let (data, length) = response.data.into_reader_and_size();
+    let mut converted = tiny_http::Response::empty(response.status_code).with_data(data, length);
+
+    /* tiny_http sets the length itself, nothing here upgrades connections */
+    for (name, value) in response.headers {
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Upgrade") {
+            continue;
+        }
+        if let Ok(header) = tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            converted.add_header(header);
+        }
+    }
+
+    /* Nothing can be done about clients that went away */
+    let _ = request.respond(converted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_requests() {
+        let request: tiny_http::Request = tiny_http::TestRequest::new()
+            .with_method(tiny_http::Method::Post)
+            .with_path("/settings?a=b")
+            .with_header("Cookie: theme=dark".parse().unwrap())
+            .into();
+        let client: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+
+        let converted = convert_request(&request, client, true, b"x=1".to_vec());
+        assert_eq!(converted.method(), "POST");
+        assert_eq!(converted.raw_url(), "/settings?a=b");
+        assert_eq!(converted.header("Cookie"), Some("theme=dark"));
+        assert_eq!(converted.remote_addr(), &client);
+        assert!(converted.is_secure());
+
+        let mut body = String::new();
+        converted.data().unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "x=1");
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, LazyLock, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConnection,
+};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/* Certificate files are looked at again at most this often */
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/* Connections without any traffic are dropped, tiny_http closes idle keep-alives sooner */
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/* Clients get much less time to finish the handshake */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/* Local port of each connection to the backend mapped to the client behind it */
+static CLIENTS: LazyLock<Mutex<HashMap<u16, SocketAddr>>> = LazyLock::new(Mutex::default);
+
+/* Backend side of every open connection, by its local port */
+type Upstreams = Arc<Mutex<HashMap<u16, TcpStream>>>;
+
+/* A running terminator, stopped in two steps on shutdown */
+pub struct Terminator {
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+    upstreams: Upstreams,
+}
+
+/* Accepts TLS on `address` and forwards the plaintext to the HTTP listener on `backend` */
+pub fn terminate(
+    address: &str,
+    backend: SocketAddr,
+    cert: &Path,
+    key: &Path,
+    max_connections: usize,
+) -> Result<Terminator, BoxError> {
+    let resolver = CertificateReloader::new(cert, key)?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let upstreams = Upstreams::default();
+
+    /* Every connection takes two threads, so their number is capped */
+    let open = Arc::new(AtomicUsize::new(0));
+
+    let acceptor = {
+        let stopping = stopping.clone();
+        let upstreams = upstreams.clone();
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                if stopping.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(slot) = Slot::take(&open, max_connections) else {
+                    log::debug!("Too many TLS connections, turning a client away");
+                    continue;
+                };
+                let config = config.clone();
+                let upstreams = upstreams.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(error) = forward(client, backend, config, &upstreams) {
+                        log::debug!("TLS connection ended: {error}");
+                    }
+                });
+            }
+        })
+    };
+
+    Ok(Terminator {
+        address,
+        stopping,
+        acceptor: Some(acceptor),
+        upstreams,
+    })
+}
+
+impl Terminator {
+    /* Closes the listening socket, open connections keep going */
+    pub fn stop_accepting(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        /* The acceptor only looks at the flag once it accepts something */
+        let mut wake = self.address;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&wake, Duration::from_secs(1));
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+
+    /* Ends every connection once the responses already written to it reached the client */
+    pub fn drain(&self, deadline: Instant) {
+        /* The backend answers the end of the requests by closing its side after the last response */
+        for upstream in self.upstreams.lock().unwrap().values() {
+            let _ = upstream.shutdown(Shutdown::Write);
+        }
+        while !self.upstreams.lock().unwrap().is_empty() {
+            if Instant::now() >= deadline {
+                log::warn!("TLS connections still open, closing them anyway");
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/* One of the open connections, given back when dropped */
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(open: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        open.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            (count < max).then_some(count + 1)
+        })
+        .ok()?;
+        Some(Self(open.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/* The client a backend connection was forwarded for, other addresses are returned as is */
+pub fn client_addr(address: SocketAddr) -> SocketAddr {
+    if !address.ip().is_loopback() {
+        return address;
+    }
+    CLIENTS
+        .lock()
+        .unwrap()
+        .get(&address.port())
+        .copied()
+        .unwrap_or(address)
+}
+
+/* Forgets the client once its backend connection is gone */
+struct Registration<'a> {
+    port: u16,
+    upstreams: &'a Upstreams,
+}
+
+impl<'a> Registration<'a> {
+    fn new(backend: &TcpStream, client: SocketAddr, upstreams: &'a Upstreams) -> io::Result<Self> {
+        let port = backend.local_addr()?.port();
+        CLIENTS.lock().unwrap().insert(port, client);
+        upstreams.lock().unwrap().insert(port, backend.try_clone()?);
+        Ok(Self { port, upstreams })
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        CLIENTS.lock().unwrap().remove(&self.port);
+        self.upstreams.lock().unwrap().remove(&self.port);
+    }
+}
+
+fn forward(
+    client: TcpStream,
+    backend: SocketAddr,
+    config: Arc<rustls::ServerConfig>,
+    upstreams: &Upstreams,
+) -> io::Result<()> {
+    /* Raised to IDLE_TIMEOUT once the handshake is done */
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    client.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let peer = client.peer_addr()?;
+    let upstream = TcpStream::connect(backend)?;
+    let _registration = Registration::new(&upstream, peer, upstreams)?;
+
+    let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+    let connection = Arc::new(Mutex::new(connection));
+
+    /* Responses are encrypted on their own thread while requests keep coming in */
+    let responses = {
+        let connection = connection.clone();
+        let mut client = client.try_clone()?;
+        let mut upstream = upstream.try_clone()?;
+        thread::spawn(move || encrypt(&connection, &mut upstream, &mut client))
+    };
+
+    let result = decrypt(&connection, &mut client.try_clone()?, &mut &upstream);
+    /* The backend closing its side lets the response thread say goodbye and finish */
+    let _ = upstream.shutdown(Shutdown::Both);
+    let _ = responses.join();
+    let _ = client.shutdown(Shutdown::Both);
+    result
+}
+
+/* Client to backend */
+fn decrypt(
+    connection: &Mutex<ServerConnection>,
+    client: &mut TcpStream,
+    upstream: &mut impl Write,
+) -> io::Result<()> {
+    let mut buffer = [0; 16 * 1024];
+    let mut handshaking = true;
+    loop {
+        let read = client.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        let mut incoming = &buffer[..read];
+        while !incoming.is_empty() {
+            let mut plaintext = Vec::new();
+            let closed = {
+                let mut connection = connection.lock().unwrap();
+                connection.read_tls(&mut incoming)?;
+                let state = match connection.process_new_packets() {
+                    Ok(state) => state,
+                    Err(error) => {
+                        /* The alert explaining the failure still goes out */
+                        let _ = send_pending(&mut connection, client);
+                        return Err(io::Error::other(error));
+                    }
+                };
+                send_pending(&mut connection, client)?;
+                if handshaking && !connection.is_handshaking() {
+                    handshaking = false;
+                    client.set_read_timeout(Some(IDLE_TIMEOUT))?;
+                    client.set_write_timeout(Some(IDLE_TIMEOUT))?;
+                }
+
+                plaintext.resize(state.plaintext_bytes_to_read(), 0);
+                connection.reader().read_exact(&mut plaintext)?;
+                state.peer_has_closed()
+            };
+
+            upstream.write_all(&plaintext)?;
+            if closed {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/* Backend to client */
+fn encrypt(
+    connection: &Mutex<ServerConnection>,
+    upstream: &mut TcpStream,
+    client: &mut TcpStream,
+) -> io::Result<()> {
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        let read = upstream.read(&mut buffer)?;
+        let mut connection = connection.lock().unwrap();
+        if read == 0 {
+            connection.send_close_notify();
+            let result = send_pending(&mut connection, client);
+            /* Wakes the request side, nothing would answer further requests */
+            let _ = client.shutdown(Shutdown::Both);
+            return result;
+        }
+        connection.writer().write_all(&buffer[..read])?;
+        send_pending(&mut connection, client)?;
+    }
+}
+
+fn send_pending(connection: &mut ServerConnection, client: &mut TcpStream) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(client)?;
+    }
+    Ok(())
+}
+
+/* Serves the certificate from disk, picking up renewals without a restart */
+#[derive(Debug)]
+struct CertificateReloader {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    certified: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl CertificateReloader {
+    fn new(cert: &Path, key: &Path) -> Result<Self, BoxError> {
+        let modified = modified(cert, key);
+        let certified = load(cert, key)?;
+        Ok(Self {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            current: RwLock::new(Loaded {
+                certified: Arc::new(certified),
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    fn reload_if_changed(&self) {
+        if self.current.read().unwrap().checked.elapsed() < RELOAD_CHECK_INTERVAL {
+            return;
+        }
+
+        let mut current = self.current.write().unwrap();
+        current.checked = Instant::now();
+        let modified = modified(&self.cert, &self.key);
+        if modified == current.modified {
+            return;
+        }
+
+        /* A renewal caught halfway is tried again on the next check */
+        match load(&self.cert, &self.key) {
+            Ok(certified) => {
+                log::info!("Reloaded TLS certificate {}", self.cert.display());
+                current.certified = Arc::new(certified);
+                current.modified = modified;
+            }
+            Err(error) => log::warn!(
+                "Keeping the current TLS certificate, {} can't be loaded: {error}",
+                self.cert.display()
+            ),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateReloader {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.reload_if_changed();
+        Some(self.current.read().unwrap().certified.clone())
+    }
+}
+
+/* Latest change of either file */
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let time = |path: &Path| {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    time(cert).max(time(key))
+}
+
+fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, BoxError> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("{}: {error}", cert.display()))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificate found", cert.display()).into());
+    }
+
+    let key =
+        PrivateKeyDer::from_pem_file(key).map_err(|error| format!("{}: {error}", key.display()))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_backend_ports_to_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let address = backend.local_addr().unwrap();
+        let client: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+
+        let upstreams = Upstreams::default();
+
+        let registration = Registration::new(&backend, client, &upstreams).unwrap();
+        assert_eq!(client_addr(address), client);
+        assert_eq!(client_addr(client), client);
+        assert!(upstreams.lock().unwrap().contains_key(&address.port()));
+
+        drop(registration);
+        assert_eq!(client_addr(address), address);
+        assert!(upstreams.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn caps_open_connections() {
+        let open = Arc::new(AtomicUsize::new(0));
+        let first = Slot::take(&open, 2).unwrap();
+        let second = Slot::take(&open, 2).unwrap();
+        assert!(Slot::take(&open, 2).is_none());
+
+        drop(first);
+        assert!(Slot::take(&open, 2).is_some());
+        drop(second);
+        assert_eq!(open.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn rejects_missing_certificates() {
+        let missing = Path::new("/nonexistent/cert.pem");
+        assert!(CertificateReloader::new(missing, missing).is_err());
+    }
+}
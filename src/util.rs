@@ -1,3 +1,19 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use ureq::http::Uri;
+
+/* Bytes a URL can't carry as they are, everything outside ASCII is encoded as well */
+const URL_UNSAFE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
 pub fn truncate(s: &str, max_chars: usize) -> &str {
     match s.char_indices().nth(max_chars) {
         None => s,
@@ -61,3 +77,23 @@ macro_rules! get_param_or_enum {
         }
     }};
 }
+
+/* A path without authority, browsers read "/\host" or "/<tab>/host" as "//host" */
+pub fn is_local(destination: &str) -> bool {
+    let bytes = destination.as_bytes();
+    if bytes.first() != Some(&b'/')
+        || bytes
+            .iter()
+            .any(|byte| *byte == b'\\' || byte.is_ascii_control())
+    {
+        return false;
+    }
+    encode_url(destination).parse::<Uri>().is_ok_and(|uri| {
+        uri.scheme().is_none() && uri.authority().is_none() && !uri.path().starts_with("//")
+    })
+}
+
+/* Makes a decoded URL fit for a Location header, e.g. "/tags/原神" */
+pub fn encode_url(url: &str) -> String {
+    utf8_percent_encode(url, URL_UNSAFE).to_string()
+}